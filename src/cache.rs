@@ -0,0 +1,512 @@
+// Binary particle cache (.sphc). Everything is little endian.
+//
+// Header, 112 bytes:
+//     magic        [u8; 4]   "SPHC"
+//     version      u32       1
+//     particles    u32       particle count of the first frame
+//     attributes   u32       ATTR_* bits, stored in bit order in every frame
+//     encoding     u32       0 f32, 1 f64, 2 positions quantized to u16 in [min, max]
+//     compression  u32       0 none, 1 deflate (per frame block)
+//     dt           f64
+//     h            f64       smoothing length, needed to mesh the cache
+//     mass         f64       particle mass, needed to mesh the cache
+//     min          3 x f64   simulation bounds
+//     max          3 x f64
+//     frames       u32
+//     reserved     u32
+//     index        u64       offset of the frame index table
+//
+// Frame blocks follow the header. Attributes are stored as planes (every x,
+// then every y, ...) which compresses much better than interleaved records:
+//     position 3 planes, density, id u64, velocity 3 planes, pressure,
+//     phase u32, age, temperature
+// Quantization only applies to positions, the other floats stay f32.
+//
+// The frame index table holds one 32 byte entry per frame:
+//     offset u64, length u64 (bytes on disk), particles u32, reserved u32, time f64
+// so any frame is one seek away.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+
+use crate::{
+    real::Real,
+    stream::{Attributes, Frame, FrameSink},
+    vectors::Vector,
+    DensityPosition,
+};
+
+const MAGIC: &[u8; 4] = b"SPHC";
+const VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 112;
+const INDEX_OFFSET: u64 = HEADER_SIZE - 8;
+
+pub const ATTR_POSITION: u32 = 1;
+pub const ATTR_DENSITY: u32 = 1 << 1;
+pub const ATTR_ID: u32 = 1 << 2;
+pub const ATTR_VELOCITY: u32 = 1 << 3;
+pub const ATTR_PRESSURE: u32 = 1 << 4;
+pub const ATTR_PHASE: u32 = 1 << 5;
+pub const ATTR_AGE: u32 = 1 << 6;
+pub const ATTR_TEMPERATURE: u32 = 1 << 7;
+
+// Cache attribute bits of the recorded frames
+pub fn attribute_bits(attributes: &Attributes) -> u32 {
+    let mut bits = ATTR_POSITION | ATTR_DENSITY | ATTR_ID;
+    for (recorded, bit) in [
+        (attributes.velocity, ATTR_VELOCITY),
+        (attributes.pressure, ATTR_PRESSURE),
+        (attributes.phase, ATTR_PHASE),
+        (attributes.age, ATTR_AGE),
+        (attributes.temperature, ATTR_TEMPERATURE),
+    ] {
+        if recorded {
+            bits |= bit;
+        }
+    }
+    bits
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    F32,
+    F64,
+    Quantized,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Deflate,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub particles: u32,
+    pub attributes: u32,
+    pub encoding: Encoding,
+    pub compression: Compression,
+    pub dt: f64,
+    pub h: f64,
+    pub mass: f64,
+    pub min: Vector,
+    pub max: Vector,
+    pub frames: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameEntry {
+    pub offset: u64,
+    pub length: u64,
+    pub particles: u32,
+    pub time: f64,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f64(reader: &mut impl Read) -> io::Result<f64> {
+    Ok(f64::from_bits(read_u64(reader)?))
+}
+
+fn read_vector(reader: &mut impl Read) -> io::Result<Vector> {
+    Ok(Vector::new(read_f64(reader)?, read_f64(reader)?, read_f64(reader)?))
+}
+
+fn write_vector(writer: &mut impl Write, v: Vector) -> io::Result<()> {
+    for k in 0..3 {
+        writer.write_all(&v.get(k).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+impl Header {
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let encoding: u32 = match self.encoding {
+            Encoding::F32 => 0,
+            Encoding::F64 => 1,
+            Encoding::Quantized => 2,
+        };
+        let compression: u32 = match self.compression {
+            Compression::None => 0,
+            Compression::Deflate => 1,
+        };
+
+        writer.write_all(MAGIC)?;
+        for value in [VERSION, self.particles, self.attributes, encoding, compression] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        for value in [self.dt, self.h, self.mass] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        write_vector(writer, self.min)?;
+        write_vector(writer, self.max)?;
+        writer.write_all(&self.frames.to_le_bytes())?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        // Index offset, patched by CacheWriter::finish
+        writer.write_all(&0u64.to_le_bytes())
+    }
+
+    fn read(reader: &mut impl Read) -> io::Result<(Self, u64)> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a particle cache"));
+        }
+        if read_u32(reader)? != VERSION {
+            return Err(invalid("unsupported particle cache version"));
+        }
+
+        let particles = read_u32(reader)?;
+        let attributes = read_u32(reader)?;
+        let encoding = match read_u32(reader)? {
+            0 => Encoding::F32,
+            1 => Encoding::F64,
+            2 => Encoding::Quantized,
+            _ => return Err(invalid("unknown encoding")),
+        };
+        let compression = match read_u32(reader)? {
+            0 => Compression::None,
+            1 => Compression::Deflate,
+            _ => return Err(invalid("unknown compression")),
+        };
+        let dt = read_f64(reader)?;
+        let h = read_f64(reader)?;
+        let mass = read_f64(reader)?;
+        let min = read_vector(reader)?;
+        let max = read_vector(reader)?;
+        let frames = read_u32(reader)?;
+        read_u32(reader)?;
+        let index = read_u64(reader)?;
+
+        let header = Self {
+            particles,
+            attributes,
+            encoding,
+            compression,
+            dt,
+            h,
+            mass,
+            min,
+            max,
+            frames,
+        };
+        Ok((header, index))
+    }
+
+    fn quantize(&self, value: f64, k: usize) -> u16 {
+        let (min, max) = (self.min.get(k), self.max.get(k));
+        let t = ((value - min) / (max - min)).clamp(0.0, 1.0);
+        (t * u16::MAX as f64).round() as u16
+    }
+
+    fn dequantize(&self, value: u16, k: usize) -> f64 {
+        let (min, max) = (self.min.get(k), self.max.get(k));
+        min + (max - min) * value as f64 / u16::MAX as f64
+    }
+
+    fn write_value(&self, block: &mut Vec<u8>, value: f64) {
+        match self.encoding {
+            Encoding::F64 => block.extend_from_slice(&value.to_le_bytes()),
+            _ => block.extend_from_slice(&(value as f32).to_le_bytes()),
+        }
+    }
+
+    fn read_value(&self, reader: &mut &[u8]) -> io::Result<f64> {
+        match self.encoding {
+            Encoding::F64 => read_f64(reader),
+            _ => {
+                let mut bytes = [0; 4];
+                reader.read_exact(&mut bytes)?;
+                Ok(f32::from_le_bytes(bytes) as f64)
+            }
+        }
+    }
+
+    fn encode<T: Real>(&self, frame: &[DensityPosition<T>]) -> Vec<u8> {
+        let mut block = Vec::new();
+        let has = |attribute: u32| self.attributes & attribute != 0;
+        // Missing optional attributes are written as zeros
+        let zero = Vector::<T>::zero();
+
+        if has(ATTR_POSITION) {
+            for k in 0..3 {
+                for p in frame {
+                    let value = p.vector.get(k).as_f64();
+                    if self.encoding == Encoding::Quantized {
+                        block.extend_from_slice(&self.quantize(value, k).to_le_bytes());
+                    } else {
+                        self.write_value(&mut block, value);
+                    }
+                }
+            }
+        }
+        if has(ATTR_DENSITY) {
+            for p in frame {
+                self.write_value(&mut block, p.density.as_f64());
+            }
+        }
+        if has(ATTR_ID) {
+            for p in frame {
+                block.extend_from_slice(&p.id.to_le_bytes());
+            }
+        }
+        if has(ATTR_VELOCITY) {
+            for k in 0..3 {
+                for p in frame {
+                    self.write_value(&mut block, p.velocity.unwrap_or(zero).get(k).as_f64());
+                }
+            }
+        }
+        if has(ATTR_PRESSURE) {
+            for p in frame {
+                self.write_value(&mut block, p.pressure.unwrap_or_default().as_f64());
+            }
+        }
+        if has(ATTR_PHASE) {
+            for p in frame {
+                block.extend_from_slice(&p.phase.unwrap_or_default().to_le_bytes());
+            }
+        }
+        if has(ATTR_AGE) {
+            for p in frame {
+                self.write_value(&mut block, p.age.unwrap_or_default().as_f64());
+            }
+        }
+        if has(ATTR_TEMPERATURE) {
+            for p in frame {
+                self.write_value(&mut block, p.temperature.unwrap_or_default().as_f64());
+            }
+        }
+        block
+    }
+
+    fn decode<T: Real>(&self, block: &[u8], particles: usize, time: f64) -> io::Result<Vec<DensityPosition<T>>> {
+        let mut reader = block;
+        let has = |attribute: u32| self.attributes & attribute != 0;
+
+        // Caches without ids identify particles by index
+        let mut frame: Vec<DensityPosition<T>> = (0..particles)
+            .map(|i| DensityPosition::new(i as u64, Vector::zero(), T::zero(), T::of(time)))
+            .collect();
+
+        if has(ATTR_POSITION) {
+            for k in 0..3 {
+                for p in frame.iter_mut() {
+                    let value = if self.encoding == Encoding::Quantized {
+                        let mut bytes = [0; 2];
+                        reader.read_exact(&mut bytes)?;
+                        self.dequantize(u16::from_le_bytes(bytes), k)
+                    } else {
+                        self.read_value(&mut reader)?
+                    };
+                    p.vector.set(k, T::of(value));
+                }
+            }
+        }
+        if has(ATTR_DENSITY) {
+            for p in frame.iter_mut() {
+                p.density = T::of(self.read_value(&mut reader)?);
+            }
+        }
+        if has(ATTR_ID) {
+            for p in frame.iter_mut() {
+                p.id = read_u64(&mut reader)?;
+            }
+        }
+        if has(ATTR_VELOCITY) {
+            for k in 0..3 {
+                for p in frame.iter_mut() {
+                    let value = T::of(self.read_value(&mut reader)?);
+                    p.velocity.get_or_insert_with(Vector::zero).set(k, value);
+                }
+            }
+        }
+        if has(ATTR_PRESSURE) {
+            for p in frame.iter_mut() {
+                p.pressure = Some(T::of(self.read_value(&mut reader)?));
+            }
+        }
+        if has(ATTR_PHASE) {
+            for p in frame.iter_mut() {
+                p.phase = Some(read_u32(&mut reader)?);
+            }
+        }
+        if has(ATTR_AGE) {
+            for p in frame.iter_mut() {
+                p.age = Some(T::of(self.read_value(&mut reader)?));
+            }
+        }
+        if has(ATTR_TEMPERATURE) {
+            for p in frame.iter_mut() {
+                p.temperature = Some(T::of(self.read_value(&mut reader)?));
+            }
+        }
+        Ok(frame)
+    }
+}
+
+pub struct CacheWriter {
+    file: BufWriter<File>,
+    header: Header,
+    index: Vec<FrameEntry>,
+    offset: u64,
+}
+
+impl CacheWriter {
+    pub fn create(path: &str, mut header: Header) -> io::Result<Self> {
+        header.frames = 0;
+        let mut file = BufWriter::new(File::create(path)?);
+        header.write(&mut file)?;
+        Ok(Self {
+            file,
+            header,
+            index: Vec::new(),
+            offset: HEADER_SIZE,
+        })
+    }
+
+    pub fn write_frame<T: Real>(&mut self, frame: &[DensityPosition<T>]) -> io::Result<()> {
+        let mut block = self.header.encode(frame);
+        if self.header.compression == Compression::Deflate {
+            let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&block)?;
+            block = encoder.finish()?;
+        }
+        self.file.write_all(&block)?;
+
+        if self.index.is_empty() {
+            self.header.particles = frame.len() as u32;
+        }
+        self.index.push(FrameEntry {
+            offset: self.offset,
+            length: block.len() as u64,
+            particles: frame.len() as u32,
+            time: frame.first().map_or(0.0, |p| p.timestamp.as_f64()),
+        });
+        self.offset += block.len() as u64;
+        Ok(())
+    }
+
+    // Appends the frame index table and fills in the header
+    pub fn finish(mut self) -> io::Result<()> {
+        for entry in &self.index {
+            self.file.write_all(&entry.offset.to_le_bytes())?;
+            self.file.write_all(&entry.length.to_le_bytes())?;
+            self.file.write_all(&entry.particles.to_le_bytes())?;
+            self.file.write_all(&0u32.to_le_bytes())?;
+            self.file.write_all(&entry.time.to_le_bytes())?;
+        }
+
+        self.header.frames = self.index.len() as u32;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.header.write(&mut self.file)?;
+        self.file.seek(SeekFrom::Start(INDEX_OFFSET))?;
+        self.file.write_all(&self.offset.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+pub struct CacheReader {
+    file: BufReader<File>,
+    header: Header,
+    index: Vec<FrameEntry>,
+}
+
+impl CacheReader {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let (header, offset) = Header::read(&mut file)?;
+        if offset == 0 {
+            return Err(invalid("particle cache was not finished"));
+        }
+
+        file.seek(SeekFrom::Start(offset))?;
+        let mut index = Vec::with_capacity(header.frames as usize);
+        for _ in 0..header.frames {
+            let offset = read_u64(&mut file)?;
+            let length = read_u64(&mut file)?;
+            let particles = read_u32(&mut file)?;
+            read_u32(&mut file)?;
+            let time = read_f64(&mut file)?;
+            index.push(FrameEntry {
+                offset,
+                length,
+                particles,
+                time,
+            });
+        }
+
+        Ok(Self { file, header, index })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn frame<T: Real>(&mut self, frame: usize) -> io::Result<Vec<DensityPosition<T>>> {
+        let entry = *self.index.get(frame).ok_or_else(|| invalid("frame out of range"))?;
+        self.file.seek(SeekFrom::Start(entry.offset))?;
+        let mut block = vec![0; entry.length as usize];
+        self.file.read_exact(&mut block)?;
+
+        if self.header.compression == Compression::Deflate {
+            let mut decoded = Vec::new();
+            DeflateDecoder::new(block.as_slice()).read_to_end(&mut decoded)?;
+            block = decoded;
+        }
+        self.header.decode(&block, entry.particles as usize, entry.time)
+    }
+}
+
+// Records the streamed frames into a cache, finished when the stream is
+pub struct CacheSink {
+    writer: Option<CacheWriter>,
+}
+
+impl CacheSink {
+    pub fn new(path: &str, header: Header) -> io::Result<Self> {
+        Ok(Self {
+            writer: Some(CacheWriter::create(path, header)?),
+        })
+    }
+}
+
+impl<T: Real> FrameSink<T> for CacheSink {
+    fn write(&mut self, index: usize, frame: &Frame<T>) {
+        if let Some(writer) = &mut self.writer {
+            if let Err(e) = writer.write_frame(frame) {
+                eprintln!("Couldn't cache frame {}: {}", index, e);
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            if let Err(e) = writer.finish() {
+                eprintln!("Couldn't finish the particle cache: {}", e);
+            }
+        }
+    }
+}
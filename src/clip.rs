@@ -0,0 +1,100 @@
+// Solid walls the fluid surface is clipped against: the simulation box, which
+// the fluid stays in, and colliders, which it stays out of. The field is
+// clamped below the iso level behind a wall so the surface closes on it, and
+// the vertices on lattice edges crossing a wall are put on the wall.
+
+use serde::Deserialize;
+
+use crate::vectors::Vector;
+
+// Solid the surface stays out of, in simulation units. The solver doesn't
+// know them, they only clip the mesh (render props standing in the fluid).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Collider {
+    Box { min: Vector, max: Vector },
+    Sphere { center: Vector, radius: f64 },
+}
+
+impl Collider {
+    // Signed distance, negative inside the solid
+    fn distance(&self, p: Vector) -> f64 {
+        match self {
+            Collider::Box { min, max } => -box_distance(p, *min, *max),
+            Collider::Sphere { center, radius } => p.subv(*center).square_size().sqrt() - radius,
+        }
+    }
+}
+
+// Signed distance to the walls of a box, positive inside it
+fn box_distance(p: Vector, min: Vector, max: Vector) -> f64 {
+    let inside = (0..3).map(|k| (p.get(k) - min.get(k)).min(max.get(k) - p.get(k))).fold(f64::INFINITY, f64::min);
+    if inside >= 0.0 {
+        return inside;
+    }
+    let outside = (0..3).map(|k| (min.get(k) - p.get(k)).max(p.get(k) - max.get(k)).max(0.0));
+    -outside.map(|d| d * d).sum::<f64>().sqrt()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Clip {
+    pub container: Option<(Vector, Vector)>,
+    pub colliders: Vec<Collider>,
+}
+
+impl Clip {
+    pub fn new(container: (Vector, Vector), colliders: Vec<Collider>) -> Self {
+        Self {
+            container: Some(container),
+            colliders,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.container.is_none() && self.colliders.is_empty()
+    }
+
+    // Signed distance to the nearest wall, positive where the fluid can be
+    pub fn free(&self, p: Vector) -> f64 {
+        let container = self.container.map_or(f64::INFINITY, |(min, max)| box_distance(p, min, max));
+        self.colliders.iter().map(|collider| collider.distance(p)).fold(container, f64::min)
+    }
+
+    // Moves a point behind a wall onto it, along the distance gradient
+    pub fn project(&self, mut p: Vector) -> Vector {
+        const STEP: f64 = 1e-6;
+        for _ in 0..4 {
+            let distance = self.free(p);
+            if distance >= 0.0 {
+                break;
+            }
+            let gradient = Vector::new(
+                self.free(p.addv(Vector::new(STEP, 0.0, 0.0))) - distance,
+                self.free(p.addv(Vector::new(0.0, STEP, 0.0))) - distance,
+                self.free(p.addv(Vector::new(0.0, 0.0, STEP))) - distance,
+            )
+            .divf(STEP);
+            let size = gradient.square_size();
+            if size == 0.0 {
+                break;
+            }
+            p = p.subv(gradient.mulf(distance / size));
+        }
+        p
+    }
+
+    // Fraction of the way from a to b where the segment meets a wall, for a
+    // free and b behind a wall
+    pub fn crossing(&self, a: Vector, b: Vector) -> f64 {
+        let (mut low, mut high) = (0.0, 1.0);
+        for _ in 0..32 {
+            let middle = (low + high) / 2.0;
+            if self.free(a.addv(b.subv(a).mulf(middle))) >= 0.0 {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+        (low + high) / 2.0
+    }
+}
@@ -0,0 +1,70 @@
+// Total Lagrangian SPH elasticity (Becker et al. 2009): each particle keeps the
+// neighborhood it had at rest. St. Venant-Kirchhoff material with Muller style
+// plasticity (Muller et al. 2004).
+
+use serde::{Deserialize, Serialize};
+
+use crate::{matrix::Matrix, real::Real};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ElasticParams {
+    pub young: f64,
+    pub poisson: f64,
+    // Elastic strain norm above which the material starts to flow (0 = never)
+    pub yield_strain: f64,
+    // Fraction of the strain above yield_strain that flows into the plastic
+    // strain per second
+    pub creep: f64,
+    pub max_plastic: f64,
+    // Damping of the relative velocity inside the body
+    pub viscosity: f64,
+}
+
+impl ElasticParams {
+    pub fn jelly() -> Self {
+        Self {
+            young: 4.0,
+            poisson: 0.3,
+            yield_strain: 0.0,
+            creep: 0.0,
+            max_plastic: 0.0,
+            viscosity: 0.5,
+        }
+    }
+
+    fn lame(&self) -> (f64, f64) {
+        let lambda = self.young * self.poisson / ((1.0 + self.poisson) * (1.0 - 2.0 * self.poisson));
+        let mu = self.young / (2.0 * (1.0 + self.poisson));
+        (lambda, mu)
+    }
+
+    // First Piola-Kirchhoff stress of the deformation gradient and the plastic
+    // strain after a step of `dt`
+    pub fn piola<T: Real>(&self, f: Matrix<T>, plastic: Matrix<T>, dt: T) -> (Matrix<T>, Matrix<T>) {
+        let green = f.transpose().mulm(f).subm(Matrix::identity()).mulf(T::of(0.5));
+        let mut plastic = plastic;
+        let mut elastic = green.subm(plastic);
+
+        if self.yield_strain > 0.0 {
+            let (yield_strain, max_plastic) = (T::of(self.yield_strain), T::of(self.max_plastic));
+            let norm = elastic.double_dot(elastic).sqrt();
+            if norm > yield_strain {
+                let flow = (T::of(self.creep) * dt).min(T::one());
+                plastic = plastic.addm(elastic.mulf(flow * (norm - yield_strain) / norm));
+            }
+
+            let norm = plastic.double_dot(plastic).sqrt();
+            if norm > max_plastic {
+                plastic = plastic.mulf(max_plastic / norm);
+            }
+            elastic = green.subm(plastic);
+        }
+
+        let (lambda, mu) = self.lame();
+        let second = Matrix::identity()
+            .mulf(T::of(lambda) * elastic.trace())
+            .addm(elastic.mulf(T::of(2.0 * mu)));
+
+        (f.mulm(second), plastic)
+    }
+}
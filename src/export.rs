@@ -0,0 +1,230 @@
+// Per-frame particle data shared by the particle exporters (VTK, Houdini .geo,
+// PLY). A frame is either taken from the solver state or rebuilt from the
+// recorded timeline, every writer then writes the attributes it is given.
+
+use std::{collections::HashMap, io};
+
+use crate::{
+    real::Real,
+    sph::SPH,
+    stream::{Attributes, Frame, FrameSink},
+    vectors::Vector,
+};
+
+// Id, position and density are always there, the rest follows the selected
+// attributes
+pub struct Particles<T: Real = f64> {
+    pub ids: Vec<u64>,
+    pub positions: Vec<Vector<T>>,
+    pub densities: Vec<T>,
+    pub velocities: Option<Vec<Vector<T>>>,
+    pub pressures: Option<Vec<T>>,
+    pub accelerations: Option<Vec<Vector<T>>>,
+    pub phases: Option<Vec<u32>>,
+    // Seconds since the particle was added
+    pub ages: Option<Vec<T>>,
+    pub temperatures: Option<Vec<T>>,
+}
+
+impl<T: Real> Particles<T> {
+    pub fn from_sph(sph: &SPH<T>, attributes: &Attributes) -> Self {
+        let n = sph.positions.len();
+        Self {
+            ids: sph.ids.clone(),
+            positions: sph.positions.clone(),
+            densities: sph.densities.clone(),
+            velocities: attributes.velocity.then(|| sph.velocities.clone()),
+            pressures: attributes.pressure.then(|| sph.pressures()),
+            accelerations: attributes.acceleration.then(|| sph.accelerations.clone()),
+            phases: attributes.phase.then(|| sph.phases.iter().map(|phase| *phase as u32).collect()),
+            ages: attributes.age.then(|| (0..n).map(|i| sph.age(i)).collect()),
+            temperatures: attributes.temperature.then(|| sph.temperatures.clone()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    // Float attributes present in this frame, density first
+    pub fn scalars(&self) -> Vec<(&'static str, &[T])> {
+        let mut scalars: Vec<(&'static str, &[T])> = vec![("density", &self.densities)];
+        for (name, values) in [
+            ("pressure", &self.pressures),
+            ("age", &self.ages),
+            ("temperature", &self.temperatures),
+        ] {
+            if let Some(values) = values {
+                scalars.push((name, values));
+            }
+        }
+        scalars
+    }
+
+    pub fn vectors(&self) -> Vec<(&'static str, &[Vector<T>])> {
+        let mut vectors: Vec<(&'static str, &[Vector<T>])> = Vec::new();
+        for (name, values) in [("velocity", &self.velocities), ("acceleration", &self.accelerations)] {
+            if let Some(values) = values {
+                vectors.push((name, values));
+            }
+        }
+        vectors
+    }
+
+    // Integer attributes present in this frame, id first
+    pub fn integers(&self) -> Vec<(&'static str, Vec<i64>)> {
+        let mut integers = vec![("id", self.ids.iter().map(|id| *id as i64).collect())];
+        if let Some(phases) = &self.phases {
+            integers.push(("phase", phases.iter().map(|phase| *phase as i64).collect()));
+        }
+        integers
+    }
+}
+
+pub trait ParticleWriter<T: Real>: Send {
+    fn write(&mut self, index: usize, time: f64, particles: &Particles<T>) -> io::Result<()>;
+}
+
+// Maps simulation axes (Z up, right handed) to the axes of the target tool,
+// output[k] = sign[k] * scale * input[axes[k]]
+#[derive(Debug, Clone, Copy)]
+pub struct CoordinateSystem {
+    pub axes: [usize; 3],
+    pub signs: [f64; 3],
+    pub scale: f64,
+}
+
+impl CoordinateSystem {
+    pub fn z_up() -> Self {
+        Self {
+            axes: [0, 1, 2],
+            signs: [1.0, 1.0, 1.0],
+            scale: 1.0,
+        }
+    }
+
+    // Houdini and most DCCs: rotation of -90° around X, stays right handed
+    pub fn y_up() -> Self {
+        Self {
+            axes: [0, 2, 1],
+            signs: [1.0, 1.0, -1.0],
+            scale: 1.0,
+        }
+    }
+
+    // Y and Z swapped, what the LuxRender scenes expect (mirrors the scene)
+    pub fn swap_yz() -> Self {
+        Self {
+            axes: [0, 2, 1],
+            signs: [1.0, 1.0, 1.0],
+            scale: 1.0,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "z-up" => Some(Self::z_up()),
+            "y-up" => Some(Self::y_up()),
+            "swap-yz" => Some(Self::swap_yz()),
+            _ => None,
+        }
+    }
+
+    pub fn with_scale(mut self, scale: f64) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn point<T: Real>(&self, v: Vector<T>) -> [f64; 3] {
+        std::array::from_fn(|k| self.signs[k] * self.scale * v.get(self.axes[k]).as_f64())
+    }
+
+    // Unit directions such as normals, converted without the scale
+    pub fn direction<T: Real>(&self, v: Vector<T>) -> [f64; 3] {
+        std::array::from_fn(|k| self.signs[k] * v.get(self.axes[k]).as_f64())
+    }
+
+    // True when the conversion flips handedness (an odd axes permutation
+    // times the signs), which reverses the triangle winding
+    pub fn is_mirror(&self) -> bool {
+        let swaps = (0..3).filter(|k| self.axes[*k] != *k).count() == 2;
+        let sign: f64 = self.signs.iter().product();
+        swaps != (sign < 0.0)
+    }
+}
+
+// Exports the recorded timeline. Attributes missing from the frames are
+// rebuilt where possible: velocities and accelerations are backward
+// differences over the previous frames (particles matched by id), pressures
+// come from the equation of state.
+pub struct ExportSink<T: Real> {
+    writers: Vec<Box<dyn ParticleWriter<T>>>,
+    attributes: Attributes,
+    kp: T,
+    rest_density: T,
+    // Time, position and velocity of every particle in the previous frame
+    previous: HashMap<u64, (T, Vector<T>, Vector<T>)>,
+}
+
+impl<T: Real> ExportSink<T> {
+    pub fn new(writers: Vec<Box<dyn ParticleWriter<T>>>, attributes: Attributes, kp: T, rest_density: T) -> Self {
+        Self {
+            writers,
+            attributes,
+            kp,
+            rest_density,
+            previous: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Real> FrameSink<T> for ExportSink<T> {
+    fn write(&mut self, index: usize, frame: &Frame<T>) {
+        let time = frame.first().map_or(T::zero(), |p| p.timestamp);
+
+        let mut velocities = Vec::with_capacity(frame.len());
+        let mut accelerations = Vec::with_capacity(frame.len());
+        for p in frame.iter() {
+            let previous = self.previous.get(&p.id).filter(|(t, _, _)| *t < time);
+            let velocity = p.velocity.unwrap_or_else(|| match previous {
+                Some((t, position, _)) => p.vector.subv(*position).divf(time - *t),
+                None => Vector::zero(),
+            });
+            let acceleration = match previous {
+                Some((t, _, v)) => velocity.subv(*v).divf(time - *t),
+                None => Vector::zero(),
+            };
+            velocities.push(velocity);
+            accelerations.push(acceleration);
+        }
+        self.previous = frame
+            .iter()
+            .zip(&velocities)
+            .map(|(p, v)| (p.id, (time, p.vector, *v)))
+            .collect();
+
+        let attributes = self.attributes;
+        let particles = Particles {
+            ids: frame.iter().map(|p| p.id).collect(),
+            positions: frame.iter().map(|p| p.vector).collect(),
+            densities: frame.iter().map(|p| p.density).collect(),
+            velocities: attributes.velocity.then_some(velocities),
+            pressures: attributes.pressure.then(|| {
+                frame
+                    .iter()
+                    .map(|p| p.pressure.unwrap_or_else(|| SPH::pressure(p.density, self.kp, self.rest_density)))
+                    .collect()
+            }),
+            accelerations: attributes.acceleration.then_some(accelerations),
+            // Only when every particle of the frame carries them
+            phases: attributes.phase.then(|| frame.iter().map(|p| p.phase).collect()).flatten(),
+            ages: attributes.age.then(|| frame.iter().map(|p| p.age).collect()).flatten(),
+            temperatures: attributes.temperature.then(|| frame.iter().map(|p| p.temperature).collect()).flatten(),
+        };
+        for writer in &mut self.writers {
+            if let Err(e) = writer.write(index, time.as_f64(), &particles) {
+                eprintln!("Couldn't export frame {}: {}", index, e);
+            }
+        }
+    }
+}
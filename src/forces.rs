@@ -0,0 +1,211 @@
+// External accelerations applied to every particle on top of the pressure,
+// viscosity and tension forces. Fields are expressed in simulation units.
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::vectors::Vector;
+
+pub trait ForceField: Send + Sync {
+    fn acceleration(&self, position: Vector, velocity: Vector, t: f64) -> Vector;
+}
+
+fn normalize(v: Vector) -> Vector {
+    let size = v.square_size().sqrt();
+    if size > 0.0 {
+        return v.divf(size);
+    }
+    v
+}
+
+// Rodrigues rotation of `v` around the unit `axis`
+fn rotate(v: Vector, axis: Vector, angle: f64) -> Vector {
+    let (sin, cos) = angle.sin_cos();
+    v.mulf(cos)
+        .addv(axis.cross(v).mulf(sin))
+        .addv(axis.mulf(axis.dot(v) * (1.0 - cos)))
+}
+
+// Uniform gravity, optionally rotating around an axis (rotating tank)
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Gravity {
+    pub acceleration: Vector,
+    pub axis: Vector,
+    pub angular_velocity: f64,
+}
+
+impl Gravity {
+    pub fn new(direction: Vector, magnitude: f64) -> Self {
+        Self {
+            acceleration: normalize(direction).mulf(magnitude),
+            axis: Vector::new(0.0, 0.0, 1.0),
+            angular_velocity: 0.0,
+        }
+    }
+
+    pub fn rotating(mut self, axis: Vector, angular_velocity: f64) -> Self {
+        self.axis = normalize(axis);
+        self.angular_velocity = angular_velocity;
+        self
+    }
+
+    pub fn at(&self, t: f64) -> Vector {
+        if self.angular_velocity == 0.0 {
+            return self.acceleration;
+        }
+        rotate(self.acceleration, self.axis, self.angular_velocity * t)
+    }
+}
+
+impl ForceField for Gravity {
+    fn acceleration(&self, _position: Vector, _velocity: Vector, t: f64) -> Vector {
+        self.at(t)
+    }
+}
+
+// Pulls particles toward the wind velocity
+pub struct Wind {
+    pub velocity: Vector,
+    pub strength: f64,
+}
+
+impl ForceField for Wind {
+    fn acceleration(&self, _position: Vector, velocity: Vector, _t: f64) -> Vector {
+        self.velocity.subv(velocity).mulf(self.strength)
+    }
+}
+
+// Negative strength makes a repulsor, falls off linearly to zero at `radius`
+pub struct Attractor {
+    pub center: Vector,
+    pub strength: f64,
+    pub radius: f64,
+}
+
+impl ForceField for Attractor {
+    fn acceleration(&self, position: Vector, _velocity: Vector, _t: f64) -> Vector {
+        let direction = self.center.subv(position);
+        let r = direction.square_size().sqrt();
+        if r >= self.radius || r == 0.0 {
+            return Vector::new(0.0, 0.0, 0.0);
+        }
+        direction.divf(r).mulf(self.strength * (1.0 - r / self.radius))
+    }
+}
+
+// Swirl around an axis going through `center`
+pub struct Vortex {
+    pub center: Vector,
+    pub axis: Vector,
+    pub strength: f64,
+    pub radius: f64,
+}
+
+impl ForceField for Vortex {
+    fn acceleration(&self, position: Vector, _velocity: Vector, _t: f64) -> Vector {
+        let axis = normalize(self.axis);
+        let offset = position.subv(self.center);
+        let radial = offset.subv(axis.mulf(axis.dot(offset)));
+        let r = radial.square_size().sqrt();
+        if r >= self.radius || r == 0.0 {
+            return Vector::new(0.0, 0.0, 0.0);
+        }
+        normalize(axis.cross(radial)).mulf(self.strength * (1.0 - r / self.radius))
+    }
+}
+
+// Smooth turbulence made of a few randomly oriented travelling waves
+pub struct Noise {
+    pub amplitude: f64,
+    pub speed: f64,
+    waves: Vec<(Vector, Vector, f64)>,
+}
+
+impl Noise {
+    pub fn new(amplitude: f64, frequency: f64, speed: f64, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut waves = Vec::new();
+        for _ in 0..8 {
+            let mut random_direction = || {
+                normalize(Vector::new(
+                    rng.gen::<f64>() - 0.5,
+                    rng.gen::<f64>() - 0.5,
+                    rng.gen::<f64>() - 0.5,
+                ))
+            };
+            let k = random_direction().mulf(frequency);
+            let direction = random_direction();
+            waves.push((k, direction, rng.gen::<f64>() * std::f64::consts::TAU));
+        }
+
+        Self {
+            amplitude,
+            speed,
+            waves,
+        }
+    }
+}
+
+impl ForceField for Noise {
+    fn acceleration(&self, position: Vector, _velocity: Vector, t: f64) -> Vector {
+        let mut a = Vector::new(0.0, 0.0, 0.0);
+        for (k, direction, phase) in &self.waves {
+            a = a.addv(direction.mulf((k.dot(position) + self.speed * t + phase).sin()));
+        }
+        a.mulf(self.amplitude / (self.waves.len() as f64).sqrt())
+    }
+}
+
+// Linear air drag
+pub struct Drag {
+    pub coefficient: f64,
+}
+
+impl ForceField for Drag {
+    fn acceleration(&self, _position: Vector, velocity: Vector, _t: f64) -> Vector {
+        velocity.mulf(-self.coefficient)
+    }
+}
+
+// Axis aligned box and time window outside of which a field does nothing
+#[derive(Debug, Clone, Copy)]
+pub struct Scope {
+    pub min: Option<Vector>,
+    pub max: Option<Vector>,
+    pub start: f64,
+    pub end: f64,
+}
+
+impl Scope {
+    pub fn everywhere() -> Self {
+        Self {
+            min: None,
+            max: None,
+            start: 0.0,
+            end: f64::INFINITY,
+        }
+    }
+
+    pub fn contains(&self, position: Vector, t: f64) -> bool {
+        if t < self.start || t > self.end {
+            return false;
+        }
+        let above = self.min.is_none_or(|min| (0..3).all(|k| position.get(k) >= min.get(k)));
+        let below = self.max.is_none_or(|max| (0..3).all(|k| position.get(k) <= max.get(k)));
+        above && below
+    }
+}
+
+pub struct Scoped {
+    pub scope: Scope,
+    pub field: Box<dyn ForceField>,
+}
+
+impl ForceField for Scoped {
+    fn acceleration(&self, position: Vector, velocity: Vector, t: f64) -> Vector {
+        if !self.scope.contains(position, t) {
+            return Vector::new(0.0, 0.0, 0.0);
+        }
+        self.field.acceleration(position, velocity, t)
+    }
+}
@@ -0,0 +1,316 @@
+// Animated glTF 2.0 export of the surface: the meshes of every frame end up in
+// one asset (.gltf + .bin, or a single .glb). Marching cubes changes the
+// topology every frame, so there is one node per frame and a step animation of
+// the node scales shows one of them at a time.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    export::CoordinateSystem,
+    mesh::{Mesh, MeshOutput, MeshWriter},
+};
+
+// glTF component types and buffer view targets
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+// Binary buffer, streamed to disk as the frames arrive, and the JSON arrays
+// describing it
+struct Builder {
+    file: BufWriter<File>,
+    length: usize,
+    views: Vec<Value>,
+    accessors: Vec<Value>,
+}
+
+impl Builder {
+    fn create(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(Self {
+            file: BufWriter::new(File::create(path)?),
+            length: 0,
+            views: Vec::new(),
+            accessors: Vec::new(),
+        })
+    }
+
+    fn push(&mut self, bytes: &[u8], target: Option<u32>) -> io::Result<usize> {
+        let mut view = json!({ "buffer": 0, "byteOffset": self.length, "byteLength": bytes.len() });
+        if let Some(target) = target {
+            view["target"] = json!(target);
+        }
+        self.file.write_all(bytes)?;
+        self.length += bytes.len();
+        self.views.push(view);
+        Ok(self.views.len() - 1)
+    }
+
+    fn accessor(&mut self, view: usize, component: u32, count: usize, kind: &str) -> usize {
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": component,
+            "count": count,
+            "type": kind,
+        }));
+        self.accessors.len() - 1
+    }
+
+    fn vectors(&mut self, values: &[[f32; 3]], target: Option<u32>) -> io::Result<usize> {
+        let bytes: Vec<u8> = values.iter().flatten().flat_map(|v| v.to_le_bytes()).collect();
+        let view = self.push(&bytes, target)?;
+        Ok(self.accessor(view, FLOAT, values.len(), "VEC3"))
+    }
+
+    fn scalars(&mut self, values: &[f32], target: Option<u32>) -> io::Result<usize> {
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let view = self.push(&bytes, target)?;
+        let accessor = self.accessor(view, FLOAT, values.len(), "SCALAR");
+        let min = values.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        self.accessors[accessor]["min"] = json!([min]);
+        self.accessors[accessor]["max"] = json!([max]);
+        Ok(accessor)
+    }
+}
+
+// Buffer opened with the first frame that has a surface, and the primitive of
+// every frame by index, None for frames without surface
+#[derive(Default)]
+struct Stream {
+    builder: Option<Builder>,
+    frames: BTreeMap<usize, Option<Value>>,
+}
+
+// The vertex and index data of every frame goes to the buffer file as it
+// arrives, only the accessors are kept for the JSON written by `finish`.
+// `frame_time` is the time between two frame indices.
+pub struct GltfWriter {
+    path: String,
+    frame_time: f64,
+    normals: bool,
+    axes: CoordinateSystem,
+    stream: Mutex<Stream>,
+}
+
+impl GltfWriter {
+    // A .glb path writes a binary asset, anything else .gltf JSON with the
+    // buffer next to it. glTF is Y up, only the scale of `output.axes` is kept.
+    pub fn new(path: &str, frame_time: f64, output: &MeshOutput) -> Self {
+        Self {
+            path: path.to_string(),
+            frame_time,
+            normals: output.normals,
+            axes: CoordinateSystem::y_up().with_scale(output.axes.scale),
+            stream: Mutex::new(Stream::default()),
+        }
+    }
+
+    fn is_glb(&self) -> bool {
+        Path::new(&self.path).extension().is_some_and(|extension| extension == "glb")
+    }
+
+    // The .bin of a .gltf, a scratch file next to a .glb copied into it at the end
+    fn buffer_path(&self) -> PathBuf {
+        let path = Path::new(&self.path);
+        if self.is_glb() {
+            return path.with_extension("glb.bin");
+        }
+        path.with_extension("bin")
+    }
+
+    // Converts to glTF axes and streams the attributes and the indices
+    fn primitive(&self, builder: &mut Builder, mesh: &Mesh) -> io::Result<Value> {
+        let positions: Vec<[f32; 3]> = mesh.positions.iter().map(|p| self.axes.point(*p).map(|x| x as f32)).collect();
+        let position = builder.vectors(&positions, Some(ARRAY_BUFFER))?;
+        let (min, max) = bounds(&positions);
+        builder.accessors[position]["min"] = json!(min);
+        builder.accessors[position]["max"] = json!(max);
+        let mut attributes = json!({ "POSITION": position });
+        if self.normals {
+            // glTF wants unit normals, vertices of degenerate triangles only
+            // have a zero one and get +Y
+            let normals: Vec<[f32; 3]> = mesh
+                .normals()
+                .iter()
+                .map(|n| if n.square_size() > 0.0 { self.axes.direction(*n).map(|x| x as f32) } else { [0.0, 1.0, 0.0] })
+                .collect();
+            attributes["NORMAL"] = json!(builder.vectors(&normals, Some(ARRAY_BUFFER))?);
+        }
+        // Application specific attributes start with an underscore:
+        // _VELOCITY, _VORTICITY, _DENSITY, _CURVATURE
+        for (name, values) in mesh.vectors(&self.axes) {
+            let values: Vec<[f32; 3]> = values.iter().map(|v| v.map(|x| x as f32)).collect();
+            attributes[attribute(name)] = json!(builder.vectors(&values, Some(ARRAY_BUFFER))?);
+        }
+        for (name, values) in mesh.scalars(&self.axes) {
+            let values: Vec<f32> = values.iter().map(|x| *x as f32).collect();
+            attributes[attribute(name)] = json!(builder.scalars(&values, Some(ARRAY_BUFFER))?);
+        }
+
+        let bytes: Vec<u8> = mesh.triangles.iter().flatten().flat_map(|i| (*i as u32).to_le_bytes()).collect();
+        let view = builder.push(&bytes, Some(ELEMENT_ARRAY_BUFFER))?;
+        let indices = builder.accessor(view, UNSIGNED_INT, mesh.triangles.len() * 3, "SCALAR");
+        Ok(json!({ "attributes": attributes, "indices": indices }))
+    }
+
+    fn build(&self, builder: &mut Builder, frames: &BTreeMap<usize, Option<Value>>) -> io::Result<Value> {
+        let mut meshes = Vec::new();
+        let mut nodes = Vec::new();
+        let first = frames.keys().next().copied().unwrap_or(0);
+        let times: Vec<f32> = frames.keys().map(|i| ((i - first) as f64 * self.frame_time) as f32).collect();
+
+        for (k, (index, primitive)) in frames.iter().enumerate() {
+            // Only the first frame is visible without playing the animation
+            let scale = if k == 0 { 1.0 } else { 0.0 };
+            let mut node = json!({ "name": format!("water_{}", index), "scale": [scale, scale, scale] });
+            // Empty accessors aren't allowed, a frame without surface gets an empty node
+            if let Some(primitive) = primitive {
+                meshes.push(json!({ "name": format!("water_{}", index), "primitives": [primitive] }));
+                node["mesh"] = json!(meshes.len() - 1);
+            }
+            nodes.push(node);
+        }
+
+        // Node k is scaled to 1 from its time to the next frame's, 0 otherwise
+        let mut samplers = Vec::new();
+        let mut channels = Vec::new();
+        if frames.len() > 1 {
+            for k in 0..frames.len() {
+                let mut keys = vec![(times[0], if k == 0 { 1.0 } else { 0.0 })];
+                if k > 0 {
+                    keys.push((times[k], 1.0));
+                }
+                if k + 1 < frames.len() {
+                    keys.push((times[k + 1], 0.0));
+                }
+                let input = builder.scalars(&keys.iter().map(|(t, _)| *t).collect::<Vec<_>>(), None)?;
+                let output = builder.vectors(&keys.iter().map(|(_, s)| [*s; 3]).collect::<Vec<_>>(), None)?;
+                samplers.push(json!({ "input": input, "output": output, "interpolation": "STEP" }));
+                channels.push(json!({ "sampler": k, "target": { "node": k, "path": "scale" } }));
+            }
+        }
+
+        let mut root = json!({
+            "asset": { "version": "2.0", "generator": "fluid" },
+            "scene": 0,
+            "scenes": [{ "nodes": (0..nodes.len()).collect::<Vec<_>>() }],
+            "nodes": nodes,
+            "meshes": meshes,
+            "buffers": [{ "byteLength": builder.length }],
+            "bufferViews": builder.views,
+            "accessors": builder.accessors,
+        });
+        // Arrays can't be empty, drop them when no frame had a surface
+        for key in ["meshes", "bufferViews", "accessors"] {
+            if root[key].as_array().is_some_and(|array| array.is_empty()) {
+                root.as_object_mut().unwrap().remove(key);
+            }
+        }
+        if builder.length == 0 {
+            root.as_object_mut().unwrap().remove("buffers");
+        }
+        if !samplers.is_empty() {
+            root["animations"] = json!([{ "name": "surface", "samplers": samplers, "channels": channels }]);
+        }
+        Ok(root)
+    }
+}
+
+fn attribute(name: &str) -> String {
+    format!("_{}", name.to_uppercase())
+}
+
+fn bounds(points: &[[f32; 3]]) -> ([f32; 3], [f32; 3]) {
+    let mut min = [f32::INFINITY; 3];
+    let mut max = [f32::NEG_INFINITY; 3];
+    for p in points {
+        for k in 0..3 {
+            min[k] = min[k].min(p[k]);
+            max[k] = max[k].max(p[k]);
+        }
+    }
+    (min, max)
+}
+
+impl MeshWriter for GltfWriter {
+    fn write(&self, frame: usize, mesh: &Mesh) -> io::Result<()> {
+        let mut stream = self.stream.lock().unwrap();
+        let stream = &mut *stream;
+        if mesh.triangles.is_empty() {
+            stream.frames.insert(frame, None);
+            return Ok(());
+        }
+        if stream.builder.is_none() {
+            stream.builder = Some(Builder::create(&self.buffer_path())?);
+        }
+        let primitive = self.primitive(stream.builder.as_mut().unwrap(), mesh)?;
+        stream.frames.insert(frame, Some(primitive));
+        Ok(())
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        let mut stream = self.stream.lock().unwrap();
+        let stream = &mut *stream;
+        if stream.frames.is_empty() {
+            return Ok(());
+        }
+        // The animation accessors need a buffer even without any surface
+        if stream.builder.is_none() {
+            stream.builder = Some(Builder::create(&self.buffer_path())?);
+        }
+        let builder = stream.builder.as_mut().unwrap();
+        let mut root = self.build(builder, &stream.frames)?;
+        builder.file.flush()?;
+        let length = builder.length;
+
+        let path = Path::new(&self.path);
+        let buffer = self.buffer_path();
+        if self.is_glb() {
+            write_glb(path, &root, &buffer, length)?;
+            return fs::remove_file(buffer);
+        }
+        if length == 0 {
+            fs::remove_file(&buffer)?;
+        } else {
+            root["buffers"][0]["uri"] = json!(buffer.file_name().unwrap().to_string_lossy());
+        }
+        fs::write(path, serde_json::to_string(&root)?)
+    }
+}
+
+// Header, then a JSON chunk padded with spaces and a BIN chunk, copied from
+// the streamed buffer file, padded with zeros, both to 4 bytes
+fn write_glb(path: &Path, root: &Value, buffer: &Path, length: usize) -> io::Result<()> {
+    let mut json = serde_json::to_vec(root)?;
+    json.resize(json.len().next_multiple_of(4), b' ');
+    let padded = length.next_multiple_of(4);
+    // The BIN chunk is left out when there is no buffer
+    let total = 12 + 8 + json.len() + if length == 0 { 0 } else { 8 + padded };
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(b"glTF")?;
+    file.write_all(&2u32.to_le_bytes())?;
+    file.write_all(&(total as u32).to_le_bytes())?;
+    file.write_all(&(json.len() as u32).to_le_bytes())?;
+    file.write_all(b"JSON")?;
+    file.write_all(&json)?;
+    if length > 0 {
+        file.write_all(&(padded as u32).to_le_bytes())?;
+        file.write_all(b"BIN\0")?;
+        io::copy(&mut File::open(buffer)?, &mut file)?;
+        file.write_all(&vec![0; padded - length])?;
+    }
+    file.flush()
+}
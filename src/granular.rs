@@ -0,0 +1,105 @@
+//https://doi.org/10.1002/nag.688
+
+// Stress based SPH for granular material with a Drucker-Prager yield criterion
+// (Bui et al. 2008). The volumetric response comes from the same equation of
+// state as the fluid, only the deviatoric stress is integrated and limited by
+// the yield surface. Stresses are positive in tension.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{eigen_value, matrix::Matrix, real::Real};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct GranularParams {
+    pub friction_angle: f64, // degrees
+    pub cohesion: f64,
+    pub shear_modulus: f64,
+}
+
+impl GranularParams {
+    pub fn dry_sand() -> Self {
+        Self {
+            friction_angle: 30.0,
+            cohesion: 0.0,
+            shear_modulus: 3.0,
+        }
+    }
+
+    // Capillary bridges in wet sand act as a small apparent cohesion
+    pub fn wet_sand() -> Self {
+        Self {
+            friction_angle: 35.0,
+            cohesion: 0.05,
+            shear_modulus: 3.0,
+        }
+    }
+
+    // Drucker-Prager constants fitted to Mohr-Coulomb (plane strain)
+    fn drucker_prager(&self) -> (f64, f64) {
+        let tan_phi = self.friction_angle.to_radians().tan();
+        let denominator = (9.0 + 12.0 * tan_phi.powi(2)).sqrt();
+        (tan_phi / denominator, 3.0 * self.cohesion / denominator)
+    }
+
+    // Cohesionless sand has no tensile strength, wet sand a little
+    fn effective_pressure<T: Real>(&self, pressure: T) -> T {
+        let (alpha, k) = self.drucker_prager();
+        if alpha > 0.0 {
+            pressure.max(T::of(-k / (3.0 * alpha)))
+        } else {
+            pressure
+        }
+    }
+
+    pub fn stress<T: Real>(&self, deviatoric: Matrix<T>, pressure: T) -> Matrix<T> {
+        deviatoric.subm(Matrix::identity().mulf(self.effective_pressure(pressure)))
+    }
+
+    // Integrate the Jaumann rate of the deviatoric stress from the velocity
+    // gradient then return it to the yield surface.
+    pub fn update_stress<T: Real>(&self, s: Matrix<T>, grad_v: Matrix<T>, pressure: T, dt: T) -> Matrix<T> {
+        let half = T::of(0.5);
+        let strain_rate = grad_v.addm(grad_v.transpose()).mulf(half);
+        let spin = grad_v.subm(grad_v.transpose()).mulf(half);
+
+        let volumetric = strain_rate.trace();
+        let deviatoric = strain_rate.subm(Matrix::identity().mulf(volumetric / T::of(3.0)));
+
+        let rate = deviatoric
+            .mulf(T::of(2.0 * self.shear_modulus))
+            .addm(s.mulm(spin.transpose()))
+            .addm(spin.mulm(s));
+
+        self.return_mapping(s.addm(rate.mulf(dt)), pressure)
+    }
+
+    // Monaghan (2000) artificial stress, only repulsive along tensile principal axes
+    pub fn artificial_stress<T: Real>(&self, sigma: Matrix<T>, rho: T, epsilon: f64) -> Matrix<T> {
+        let (eiv, eig) = eigen_value::eigen(sigma.cast::<f64>().to_array());
+        let rho = rho.as_f64();
+
+        let mut r = [[0.0; 3]; 3];
+        for l in 0..3 {
+            for m in 0..3 {
+                for n in 0..3 {
+                    if eig[n] > 0.0 {
+                        r[l][m] += eiv[l][n] * eiv[m][n] * (-epsilon * eig[n] / rho.powi(2));
+                    }
+                }
+            }
+        }
+        Matrix::new(r).cast()
+    }
+
+    // With I1 = -3p the yield surface is sqrt(J2) = 3 alpha p + k
+    fn return_mapping<T: Real>(&self, s: Matrix<T>, pressure: T) -> Matrix<T> {
+        let (alpha, k) = self.drucker_prager();
+
+        let limit = (T::of(3.0 * alpha) * self.effective_pressure(pressure) + T::of(k)).max(T::zero());
+        let sqrt_j2 = (T::of(0.5) * s.double_dot(s)).sqrt();
+        if sqrt_j2 > limit {
+            return s.mulf(limit / sqrt_j2);
+        }
+        s
+    }
+}
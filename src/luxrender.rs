@@ -3,14 +3,13 @@
 // Convert simulation to luxrender usable files
 
 use std::{
-    collections::{HashMap, BTreeSet},
-    f64::consts::PI,
-    ops::*, fs::{OpenOptions, self}, sync::Arc,
+    collections::HashMap,
+    fs::{OpenOptions, self, File}, sync::Arc,
 };
-use std::io::Write;
+use std::io::{self, BufWriter, Write};
 use rayon::prelude::*;
 
-use crate::{eigen_value, vectors::Vector, DensityPosition};
+use crate::{clip::Clip, export::CoordinateSystem, mesh::{Mesh, MeshAttributes, MeshOutput, MeshWriter}, real::Real, reconstruction::{self, Mesher, Reconstruction, ReconstructionParams}, surface_nets, postprocess, vectors::Vector, voxel::{self, VoxelGrid, BLOCK}, volume::Volume, DensityPosition, whitewater::{DiffuseKind, DiffuseParticle}};
 
 static EDGE_TABLE: [i64; 256] = [
     0x0, 0x109, 0x203, 0x30a, 0x406, 0x50f, 0x605, 0x70c, 0x80c, 0x905, 0xa0f, 0xb06, 0xc0a, 0xd03,
@@ -301,347 +300,155 @@ static DX: [usize; 8] = [0, 0, 1, 1, 0, 0, 1, 1];
 static DY: [usize; 8] = [0, 1, 1, 0, 0, 1, 1, 0];
 static DZ: [usize; 8] = [0, 0, 0, 0, 1, 1, 1, 1];
 
-static POLY6C: f64 = 315.0_f64 / 64.0_f64 / PI;
-static KSCALE: f64 = 1.0 / 2.5;
-static ISOLEVEL: f64 = 0.08;
+// Corners of every cube edge, always from the lower to the upper lattice point
+static EDGE_CORNERS: [(usize, usize); 12] = [
+    (0, 1), (1, 2), (3, 2), (0, 3), (4, 5), (5, 6), (7, 6), (4, 7), (0, 4), (1, 5), (2, 6), (3, 7),
+];
+
+// Surface crossing on the lattice edge a-b. Behind a wall of `clip` the field
+// is clamped to `iso`, the crossing is then the wall when it comes first.
+pub fn vertex_interpret<T: Real>(
+    (a, v_a): ([usize; 3], T),
+    (b, v_b): ([usize; 3], T),
+    min_vector: Vector<T>,
+    baselen: T,
+    iso: T,
+    clip: &Clip,
+) -> Vector<T> {
+    let lattice = |[l, m, n]: [usize; 3]| {
+        Vector::new(T::of(l as f64), T::of(m as f64), T::of(n as f64))
+            .mulf(baselen)
+            .addv(min_vector)
+    };
+    let (p_a, p_b) = (lattice(a), lattice(b));
+
+    let p = if (v_a - v_b).abs() > T::of(1e-5) {
+        p_a.addv(p_b.subv(p_a).mulf(iso.sub(v_a).div(v_b - v_a)))
+    } else {
+        p_a
+    };
+    if clip.is_empty() {
+        return p;
+    }
 
-fn poly6(u2: f64) -> f64 {
-    if u2 > 1.0 {
-        return 0.0;
+    let (free_a, free_b) = (clip.free(p_a.cast()) >= 0.0, clip.free(p_b.cast()) >= 0.0);
+    if free_a == free_b {
+        return p;
     }
-    let d = 1.0 - u2;
-    POLY6C.mul(d.powi(3))
+    let (from, to) = if free_a { (p_a, p_b) } else { (p_b, p_a) };
+    let wall = from.addv(to.subv(from).mulf(T::of(clip.crossing(from.cast(), to.cast()))));
+    if p.subv(from).square_size() < wall.subv(from).square_size() {
+        return p;
+    }
+    wall
 }
 
-fn vertex_interpret(
-    a: String,
-    b: String,
-    min_vector: Vector,
-    baselen: f64,
-    valuemap: &mut HashMap<String, f64>,
-    edgepos: &mut Vec<Vector>,
-    edgemap: &mut HashMap<String, usize>,
-) -> usize {
-    let mut test_string = a.clone();
-    test_string.push_str(&b);
-    if !edgemap.contains_key(&test_string) {
-        if !valuemap.contains_key(&a.clone()) {
-            valuemap.insert(a.clone(), 0.0);
-        }
-        let v_a = valuemap[&a];
+// One file per diffuse kind so each can get its own material
+pub fn write_diffuse(frame: usize, particles: &[DiffuseParticle], radius: f64) {
+    fs::create_dir_all("./render").unwrap();
 
-        if !valuemap.contains_key(&b.clone()) {
-            valuemap.insert(b.clone(), 0.0);
-        }
-        let v_b = valuemap[&b];
-
-        let vec = a.split(":").collect::<Vec<&str>>();
-        let l = vec[0].parse::<usize>().unwrap();
-        let m = vec[1].parse::<usize>().unwrap();
-        let n = vec[2].parse::<usize>().unwrap();
-        let mut p_a = Vector::new(l as f64, m as f64, n as f64);
-
-        p_a = p_a.mulf(baselen)
-            .addv(min_vector);
-
-        let vec = b.split(":").collect::<Vec<&str>>();
-        let l = vec[0].parse::<usize>().unwrap();
-        let m = vec[1].parse::<usize>().unwrap();
-        let n = vec[2].parse::<usize>().unwrap();
-        let mut p_b = Vector::new(l as f64, m as f64, n as f64);
-        p_b = p_b.mulf(baselen)
-            .addv(min_vector);
-            
-        let p: Vector;
-
-        if (v_a - v_b).abs() > 1e-5 {
-            p = p_a.addv(p_b.subv(p_a).mulf(ISOLEVEL.sub(v_a).div(v_b - v_a)));
-        } else {
-            p = p_a;
-        }
+    for (kind, name) in [
+        (DiffuseKind::Spray, "spray"),
+        (DiffuseKind::Foam, "foam"),
+        (DiffuseKind::Bubble, "bubble"),
+    ] {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(format!("./render/{}_{}", name, frame))
+            .unwrap();
 
-        edgepos.push(p);
-        if edgemap.contains_key(&test_string) {
-            let value = edgemap.get_mut(&test_string).unwrap();
-            *value = edgepos.len() - 1;
-        }else {
-            edgemap.insert(test_string.clone(), edgepos.len() - 1);
+        for p in particles.iter().filter(|p| p.kind == kind) {
+            let [x, y, z] = CoordinateSystem::swap_yz().point(p.position);
+            if let Err(e) = writeln!(
+                file,
+                "AttributeBegin Translate {} {} {} Shape \"sphere\" \"float radius\" [{}] AttributeEnd",
+                x,
+                y,
+                z,
+                radius
+            ) {
+                eprintln!("Couldn't write to file: {}", e);
+            }
         }
     }
-    return edgemap[&test_string];
 }
 
-pub struct Renderer {
-    particle_amount: usize,
+pub struct Renderer<T: Real = f64> {
     frame: usize,
 
-    input: Arc<Vec<DensityPosition>>,
-    h: f64,
-    mass: f64,
-
-    min_vector: Vector,
-    max_vector: Vector,
-
-    det_g: Vec<f64>,
-    g: Vec<Vec<Vec<f64>>>,
-    bbox: Vec<Vec<Vector>>,
-    new_pos: Vec<Vector>,
-    preprocess_grid: Vec<Vec<Vec<Vec<usize>>>>,
-    edgemap: HashMap<String, usize>,
-    valuemap: HashMap<String, f64>,
-    gridset: BTreeSet<String>,
-    edgepos: Vec<Vector>,
+    input: Arc<Vec<DensityPosition<T>>>,
+    h: T,
+    mass: T,
+
+    min_vector: Vector<T>,
+    max_vector: Vector<T>,
+
+    field: VoxelGrid<T>,
+
+    // Neighbouring frames blended into the field, by distance to this one
+    neighbors: Vec<(usize, Arc<Vec<DensityPosition<T>>>)>,
+
+    pub params: ReconstructionParams,
+    // Walls the surface is clipped against, none by default
+    pub clip: Clip,
+    // Built from `params` at the first frame and whenever they change
+    reconstruction: Option<(ReconstructionParams, Box<dyn Reconstruction<T>>)>,
+
+    // Vertex attributes computed on the mesh, none by default
+    pub attributes: MeshAttributes,
 }
 
-impl Renderer {
-    pub fn new(
-        particle_amount: usize,
-        frame: usize,
-        input: Arc<Vec<DensityPosition>>,
-        h: f64,
-        mass: f64,
-    ) -> Self {
+impl<T: Real> Renderer<T> {
+    pub fn new(frame: usize, input: Arc<Vec<DensityPosition<T>>>, h: T, mass: T) -> Self {
         Self {
-            particle_amount,
             frame,
             input,
             h,
             mass,
-            min_vector: Vector::new(0.0, 0.0, 0.0),
-            max_vector: Vector::new(0.0, 0.0, 0.0),
-            det_g: vec![0.0; particle_amount],
-            g: vec![vec![vec![0.0; 3]; 3]; particle_amount],
-            bbox: vec![vec![Vector::new(0.0, 0.0, 0.0); 2]; particle_amount],
-            new_pos: vec![Vector::new(0.0, 0.0, 0.0); particle_amount],
-            preprocess_grid: vec![vec![vec![Vec::<usize>::new(); 120]; 120]; 120],
-            valuemap: HashMap::new(),
-            gridset: BTreeSet::new(),
-            edgemap: HashMap::new(),
-            edgepos: Vec::new(),
-        }
-    }
-    pub fn getvaluemap(&self, idx: String) -> f64 {
-        if self.valuemap.contains_key(&idx) {
-            return *self.valuemap.get(&idx).unwrap();
-        }
-        return 0.0;
-    }
-    pub fn weight(r: f64, h: f64) -> f64 {
-        if r >= 2.0 * h {
-            return 0.0;
-        }
-        let d = r / (2.0 * h);
-        return 1.0 - d * d * d;
-    }
-
-    pub fn preprocess(&mut self, diff_vector: Vector) {
-        let preprocess_grid = &mut self.preprocess_grid; //vec![vec![vec![Vec::<usize>::new(); 120];120];120];
-
-        let length = Vector::new(
-            diff_vector.get_x().div(100.0).max(2.0 * self.h),
-            diff_vector.get_y().div(100.0).max(2.0 * self.h),
-            diff_vector.get_z().div(100.0).max(2.0 * self.h),
-        );
-
-        
-     
-        for i in 0..self.particle_amount {
-            let input = self.input[i + self.frame * self.particle_amount].vector;
-            let grid_x = input
-                .get_x()
-                .sub(self.min_vector.get_x())
-                .div(length.get_x())
-                .ceil() as usize;
-            let grid_y = input
-                .get_y()
-                .sub(self.min_vector.get_y())
-                .div(length.get_y())
-                .ceil() as usize;
-            let grid_z = input
-                .get_z()
-                .sub(self.min_vector.get_z())
-                .div(length.get_z())
-                .ceil() as usize;
-            preprocess_grid[grid_x][grid_y][grid_z].push(i);
-            
-        }
-
-        ((0..self.particle_amount), &mut self.det_g, &mut self.bbox, &mut self.new_pos, &mut self.g).into_par_iter().for_each(|(i, det_g, bbox, new_pos_g, g) | {
-            let mut neighbor = 0;
-            let mut sum_wij = 0.0;
-            let mut cov = [[0.0; 3]; 3];
-
-            let mut new_pos = Vector::new(0.0, 0.0, 0.0);
-            let input = self.input[i + self.frame * self.particle_amount].vector;
-            let grid_x = input
-                .get_x()
-                .sub(self.min_vector.get_x())
-                .div(length.get_x())
-                .ceil() as i32;
-            let grid_y = input
-                .get_y()
-                .sub(self.min_vector.get_y())
-                .div(length.get_y())
-                .ceil() as i32;
-            let grid_z = input
-                .get_z()
-                .sub(self.min_vector.get_z())
-                .div(length.get_z())
-                .ceil() as i32;
-
-            for x in 0..3_i32 {
-                if x.sub(1).add(grid_x) < 0 {
-                    continue;
-                }
-                for y in 0..3_i32 {
-                    if y.sub(1).add(grid_y) < 0 {
-                        continue;
-                    }
-                    for z in 0..3_i32 {
-                        if z.sub(1).add(grid_z) < 0 {
-                            continue;
-                        }
-
-                        for j in &preprocess_grid[grid_x.sub(1).add(x) as usize]
-                            [grid_y.sub(1).add(y) as usize]
-                            [grid_z.sub(1).add(z) as usize]
-                        {
-                            let j_vector = self.input[*j + self.frame * self.particle_amount].vector;
-                            let r = input.subv(j_vector).square_size().sqrt();
-                            let wij = Self::weight(r, self.h);
-                            if wij == 0.0 {
-                                continue;
-                            }
-                            
-                            neighbor += 1;
-                            sum_wij += wij;
-
-                            new_pos = new_pos.addv(j_vector.mulf(wij));
-                        }
-                    }
-                }
-            }
-
-            new_pos = new_pos.divf(sum_wij);
-
-            for x in 0..3_i32 {
-                if x.sub(1).add(grid_x) < 0 {
-                    continue;
-                }
-                for y in 0..3_i32 {
-                    if y.sub(1).add(grid_y) < 0 {
-                        continue;
-                    }
-                    for z in 0..3_i32 {
-                        if z.sub(1).add(grid_z) < 0 {
-                            continue;
-                        }
-
-                        for j in &preprocess_grid[grid_x.sub(1).add(x) as usize]
-                            [grid_y.sub(1).add(y) as usize]
-                            [grid_z.sub(1).add(z) as usize]
-                        {
-                            let j_vector = self.input[*j + self.frame * self.particle_amount].vector;
-                            let r = input.subv(j_vector).square_size().sqrt();
-                            let wij = Self::weight(r, self.h);
-                            if wij == 0.0 {
-                                continue;
-                            }
-
-                            let delta = j_vector.subv(new_pos);
-                            for l in 0..3 {
-                                for m in 0..3 {
-                                    cov[l as usize][m as usize] +=
-                                        wij * delta.get(l) * delta.get(m);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-
-            for l in 0..3 {
-                for m in 0..3 {
-                    cov[l as usize][m as usize] /= sum_wij;
-                }
-            }
-
-            let (eiv,  mut eig) = eigen_value::eigen(cov);
-
-            eig[0] = eig[0].max(eig[2] / 5.0);
-            eig[1] = eig[1].max(eig[2] / 5.0);
-
-            if neighbor < 35 {
-                eig[0] = 0.6;
-                eig[1] = 0.6;
-                eig[2] = 0.6;
-            } else {
-                for j in 0..3 {
-                    eig[j] *= KSCALE;
-                }
-            }
-
-            let mut m = [[0.0_f64; 3]; 3];
-            *det_g = 1.0;
-            for l in 0..3 {
-                for n in 0..3 {
-                    m[l][n] = eiv[l][n] * eig[n] * self.h;
-                    g[l][n] = eiv[n][l] / eig[l] / self.h;
-                }
-                *det_g *= eig[l] / self.h;
-            }
-
-            let halfbox = Vector::new(
-                Vector::new(m[0][0], m[0][1], m[0][2]).square_size().sqrt(),
-                Vector::new(m[1][0], m[1][1], m[1][2]).square_size().sqrt(),
-                Vector::new(m[2][0], m[2][1], m[2][2]).square_size().sqrt(),
-            );
-
-            bbox[0] = new_pos.subv(halfbox);
-            bbox[1] = new_pos.addv(halfbox);
-            *new_pos_g = new_pos.clone();
-        });
-        
-
-        for i in 0..self.particle_amount {
-            let grid_x = self.input[i + self.frame * self.particle_amount]
-                .vector
-                .get_x()
-                .sub(self.min_vector.get_x())
-                .div(length.get_x())
-                .ceil() as usize;
-            let grid_y = self.input[i + self.frame * self.particle_amount]
-                .vector
-                .get_y()
-                .sub(self.min_vector.get_y())
-                .div(length.get_y())
-                .ceil() as usize;
-            let grid_z = self.input[i + self.frame * self.particle_amount]
-                .vector
-                .get_z()
-                .sub(self.min_vector.get_z())
-                .div(length.get_z())
-                .ceil() as usize;
-            self.preprocess_grid[grid_x][grid_y][grid_z].pop();
+            min_vector: Vector::zero(),
+            max_vector: Vector::zero(),
+            field: VoxelGrid::new(),
+            neighbors: Vec::new(),
+            params: ReconstructionParams::default(),
+            clip: Clip::default(),
+            reconstruction: None,
+            attributes: MeshAttributes::none(),
         }
     }
 
-    pub fn set_frame(&mut self, frame: usize) {
+    // Input holds the particles of this frame only
+    pub fn set_frame(&mut self, frame: usize, input: Arc<Vec<DensityPosition<T>>>) {
         self.frame = frame;
+        self.input = input;
     }
 
-    pub fn generate(&mut self) {
-
-        let mut min_vector = Vector::new(0.0, 0.0, 0.0);
-        let mut max_vector = Vector::new(0.0, 0.0, 0.0);
-
-        let mut matrix = Vec::<(isize, isize, isize)>::new();
+    // Frames around this one for `temporal_frames`, empty otherwise
+    pub fn set_neighbors(&mut self, neighbors: Vec<(usize, Arc<Vec<DensityPosition<T>>>)>) {
+        self.neighbors = neighbors;
+    }
 
-        for i in 0..self.particle_amount {
-            let current = self.input[i + self.frame * self.particle_amount];
+    // Surface and volume of the frame, each when asked for, out of one
+    // splatting pass
+    pub fn render(&mut self, surface: bool, volume: bool) -> (Option<Mesh>, Option<Volume>) {
+        let input = Arc::clone(&self.input);
+        // Falloff to the power of the distance, this frame weighs 1
+        let neighbors: Vec<(f64, Arc<Vec<DensityPosition<T>>>)> = self
+            .neighbors
+            .iter()
+            .map(|(distance, particles)| (self.params.temporal_falloff.powi(*distance as i32), Arc::clone(particles)))
+            .collect();
+
+        let mut min_vector = Vector::zero();
+        let mut max_vector = Vector::zero();
+
+        // The neighbouring frames' kernels are computed on the same grid
+        let particles = input.iter().chain(neighbors.iter().flat_map(|(_, particles)| particles.iter()));
+        for (i, current) in particles.enumerate() {
             if i == 0 {
-                min_vector = current.vector.clone();
-                max_vector = current.vector.clone();
+                min_vector = current.vector;
+                max_vector = current.vector;
             }
             min_vector.set_x(min_vector.get_x().min(current.vector.get_x()));
             min_vector.set_y(min_vector.get_y().min(current.vector.get_y()));
@@ -653,293 +460,414 @@ impl Renderer {
         }
         
         let h_vector = Vector::new(self.h, self.h, self.h);
-        self.min_vector = min_vector.subv(h_vector.mulf(4.0));
-        self.max_vector = max_vector.addv(h_vector.mulf(4.0));
+        let padding = T::of(self.params.padding);
+        self.min_vector = min_vector.subv(h_vector.mulf(padding));
+        self.max_vector = max_vector.addv(h_vector.mulf(padding));
         min_vector = self.min_vector;
         max_vector = self.max_vector;
 
-        let diff = max_vector.subv(min_vector);
-        let baselen = 0.23;
-
-        self.preprocess(diff);
-
-
-        for i in 0..self.particle_amount {
-            let bottom_left = self.bbox[i][0].subv(min_vector).divf(baselen);
-            let bottom_left = Vector::new(
-                bottom_left.get_x().ceil(),
-                bottom_left.get_y().ceil(),
-                bottom_left.get_z().ceil(),
-            );
-
-            let top_right = self.bbox[i][1].subv(min_vector).divf(baselen);
-            let top_right = Vector::new(
-                top_right.get_x().floor(),
-                top_right.get_y().floor(),
-                top_right.get_z().floor(),
-            );
-            for l in (bottom_left.get_x() as usize)..=(top_right.get_x() as usize) {
-                for m in (bottom_left.get_y() as usize)..=(top_right.get_y() as usize) {
-                    for n in (bottom_left.get_z() as usize)..=(top_right.get_z() as usize) {
-                        let mut r = Vector::new(l as f64, m as f64, n as f64);
-                        r = r.mulf(baselen).addv(min_vector).subv(self.new_pos[i]);
-                        let mut gr = Vector::new(0.0, 0.0, 0.0);
-                        for p in 0..3 {
-                            for q in 0..3 {
-                                let new_value = self.g[i][p][q].mul(r.get(q));
-                                gr.set(p, gr.get(p).add(new_value));
-                            }
-                        }
-                        
-                        let weight = poly6(gr.square_size());
-
-                        if weight != 0.0 {
-                            let triv = format!("{}:{}:{}", l, m, n);
-                            if self.valuemap.contains_key(&triv) {
-                                let value = self.valuemap.get_mut(&triv).unwrap();
-                                *value += self.mass / self.input[i + self.frame * self.particle_amount].density * self.det_g[i] * weight;
-                            } else {
-                                self.valuemap.insert(triv.clone(), self.mass / self.input[i + self.frame * self.particle_amount].density * self.det_g[i] * weight);
-                            }
-                        }
+        let baselen = T::of(self.params.voxel_size);
 
+        if !self.reconstruction.as_ref().is_some_and(|(params, _)| *params == self.params) {
+            self.reconstruction = Some((self.params, reconstruction::build(self.params)));
+        }
+        let bounds = (min_vector, max_vector);
+        let (_, method) = self.reconstruction.as_mut().unwrap();
+        let channels = if neighbors.is_empty() || method.blends_kernels() {
+            method.prepare(&input, self.h, self.mass, bounds);
+            method.blend(&input, &neighbors, self.h, self.mass, bounds);
+            self.splat(&input, min_vector, baselen)
+        } else {
+            // The weighted average of the sums splatted by every frame, this
+            // one last so the kernels stay prepared for it
+            let total: f64 = 1.0 + neighbors.iter().map(|(weight, _)| weight).sum::<f64>();
+            let frames = neighbors.iter().map(|(weight, particles)| (*weight, &particles[..])).chain([(1.0, &input[..])]);
+            let mut sums: HashMap<u64, Vec<T>> = HashMap::new();
+            for (weight, particles) in frames {
+                let (_, method) = self.reconstruction.as_mut().unwrap();
+                method.prepare(particles, self.h, self.mass, bounds);
+                let weight = T::of(weight / total);
+                for (block, values) in self.splat(particles, min_vector, baselen) {
+                    let sum = sums.entry(block).or_insert_with(|| vec![T::zero(); values.len()]);
+                    for (sum, value) in sum.iter_mut().zip(values) {
+                        *sum += value * weight;
                     }
                 }
             }
-
-            for l in (bottom_left.get_x().sub(1.0) as isize)..=(top_right.get_x().add(1.0) as isize)
-            {
-                for m in (bottom_left.get_y().sub(1.0) as isize)..=(top_right.get_y().add(1.0) as isize)
-                {
-                    for n in (bottom_left.get_z().sub(1.0) as isize)..=(top_right.get_z().add(1.0) as isize)
-                    {
-                        let triv = format!("{}:{}:{}", l, m, n);
-                        self.gridset.insert(triv);
-                    }
-                }
+            sums.into_iter().collect()
+        };
+        self.field = self.to_field(channels, min_vector, baselen);
+        if !self.clip.is_empty() {
+            // Behind the walls, at most on the surface
+            let iso = self.method().iso_level();
+            let clip = &self.clip;
+            let lattice = |[l, m, n]: [usize; 3]| {
+                Vector::new(T::of(l as f64), T::of(m as f64), T::of(n as f64))
+                    .mulf(baselen)
+                    .addv(min_vector)
+            };
+            self.field.clamp_where(iso, |point| clip.free(lattice(point).cast()) < 0.0);
+        }
+        let volume = volume.then(|| Volume::new(&self.field, min_vector, baselen));
+        if !surface {
+            self.field.clear();
+            return (None, volume);
+        }
+        let (edgepos, matrix) = match self.params.mesher {
+            Mesher::MarchingCubes => self.polygonize(min_vector, baselen),
+            Mesher::SurfaceNets => {
+                surface_nets::polygonize(&self.field, self.method().iso_level(), min_vector, baselen, &self.clip)
             }
+        };
+
+        let mut mesh = Mesh::new(edgepos.iter().map(|p| p.cast()).collect(), matrix);
+        postprocess::post_process(&mut mesh, &self.params);
+        self.interpolate(&mut mesh);
+        if self.attributes.curvature {
+            mesh.curvatures = Some(mesh.mean_curvatures());
         }
 
-        //convert ggridset to vector
+        //clear
+        self.field.clear();
 
-        for it in self.gridset.iter() {
-            let vec = it.split(":").collect::<Vec<&str>>();
-            let grid_x = vec[0].parse::<usize>().unwrap();
-            let grid_y = vec[1].parse::<usize>().unwrap();
-            let grid_z = vec[2].parse::<usize>().unwrap();
+        (Some(mesh), volume)
+    }
 
-            let mut grid_p = vec![String::new(); 8];
+    fn method(&self) -> &dyn Reconstruction<T> {
+        &*self.reconstruction.as_ref().unwrap().1
+    }
 
-            for ty in 0..8 {
-                let px = grid_x + DX[ty];
-                let py = grid_y + DY[ty];
-                let pz = grid_z + DZ[ty];
-                let triv = format!("{}:{}:{}", px, py, pz);
+    // Lattice points reached by the kernel of particle i
+    fn lattice_range(&self, i: usize, min_vector: Vector<T>, baselen: T) -> ([usize; 3], [usize; 3]) {
+        let [bottom_left, top_right] = self.method().support(i);
+        let bottom_left = bottom_left.subv(min_vector).divf(baselen);
+        let top_right = top_right.subv(min_vector).divf(baselen);
+        (
+            std::array::from_fn(|k| bottom_left.get(k).ceil().as_f64() as usize),
+            std::array::from_fn(|k| top_right.get(k).floor().as_f64() as usize),
+        )
+    }
 
-                grid_p[ty] = triv;
+    // Accumulates the channels block by block in parallel. Every block sums
+    // the particles reaching it in index order, the result doesn't depend on
+    // the thread count. Blocks none of them adds to are left out.
+    fn splat(&self, particles: &[DensityPosition<T>], min_vector: Vector<T>, baselen: T) -> Vec<(u64, Vec<T>)> {
+        let method = self.method();
+        let channels = method.channels();
+        let ranges: Vec<([usize; 3], [usize; 3])> =
+            (0..particles.len()).map(|i| self.lattice_range(i, min_vector, baselen)).collect();
+
+        let mut reached: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (i, (low, high)) in ranges.iter().enumerate() {
+            if (0..3).any(|k| low[k] > high[k]) {
+                continue;
             }
-            
-            let mut cubeindex = 0;
-            for ty in 0..8 {
-                if self.getvaluemap(grid_p[ty].clone()) > ISOLEVEL {
-                    cubeindex |= 1 << ty;
+            let [low, high] = [low, high].map(|corner| corner.map(|c| c / BLOCK));
+            for n in low[2]..=high[2] {
+                for m in low[1]..=high[1] {
+                    for l in low[0]..=high[0] {
+                        reached.entry(voxel::pack([l, m, n])).or_default().push(i);
+                    }
                 }
             }
+        }
 
-            if EDGE_TABLE[cubeindex] == 0 {
-                continue;
-            }
+        let lattice = |[l, m, n]: [usize; 3]| {
+            Vector::new(T::of(l as f64), T::of(m as f64), T::of(n as f64))
+                .mulf(baselen)
+                .addv(min_vector)
+        };
+        reached
+            .into_par_iter()
+            .filter_map(|(block, particles)| {
+                let origin = voxel::unpack(block).map(|c| c * BLOCK);
+                let mut values = vec![T::zero(); BLOCK * BLOCK * BLOCK * channels];
+                let mut touched = false;
+                for i in particles {
+                    let (low, high) = ranges[i];
+                    let low: [usize; 3] = std::array::from_fn(|k| low[k].max(origin[k]));
+                    let high: [usize; 3] = std::array::from_fn(|k| high[k].min(origin[k] + BLOCK - 1));
+                    for n in low[2]..=high[2] {
+                        for m in low[1]..=high[1] {
+                            for l in low[0]..=high[0] {
+                                let offset = (l - origin[0]) + BLOCK * ((m - origin[1]) + BLOCK * (n - origin[2]));
+                                let point = &mut values[offset * channels..(offset + 1) * channels];
+                                touched |= method.splat(i, lattice([l, m, n]), point);
+                            }
+                        }
+                    }
+                }
+                touched.then_some((block, values))
+            })
+            .collect()
+    }
 
-            
-            let mut vertlist = [-1_isize; 12];
+    // Field of the blocks from their channel sums
+    fn to_field(&self, blocks: Vec<(u64, Vec<T>)>, min_vector: Vector<T>, baselen: T) -> VoxelGrid<T> {
+        let method = self.method();
+        let channels = method.channels();
+        let lattice = |[l, m, n]: [usize; 3]| {
+            Vector::new(T::of(l as f64), T::of(m as f64), T::of(n as f64))
+                .mulf(baselen)
+                .addv(min_vector)
+        };
+        let blocks: Vec<(u64, Vec<T>)> = blocks
+            .into_par_iter()
+            .map(|(block, values)| {
+                let field = VoxelGrid::<T>::points(block)
+                    .zip(values.chunks(channels))
+                    .map(|(point, values)| method.field(lattice(point), values))
+                    .collect();
+                (block, field)
+            })
+            .collect();
+        VoxelGrid::from_blocks(blocks)
+    }
 
-            let valuemap = &mut self.valuemap;
-            let edgepos = &mut self.edgepos;
-            let edgemap = &mut self.edgemap;
-            
-            
+    // Marching cubes over the blocks in parallel. A surface vertex belongs to
+    // the block holding the lower end of its edge, so the cubes on both sides
+    // of a block seam share it. Returns the vertices and triangles.
+    fn polygonize(&self, min_vector: Vector<T>, baselen: T) -> (Vec<Vector<T>>, Vec<[usize; 3]>) {
+        let field = &self.field;
+        let iso = self.method().iso_level();
+        let blocks = field.active_blocks();
+
+        // Vertices of the edges crossing the surface, by owning block
+        let vertices: Vec<Vec<(u64, Vector<T>)>> = blocks
+            .par_iter()
+            .map(|block| {
+                let values = field.padded(*block);
+                let origin = voxel::unpack(*block).map(|c| c * BLOCK);
+                let value = |p: [usize; 3]| values[voxel::padded_offset(std::array::from_fn(|k| p[k] - origin[k]))];
+
+                let mut vertices = Vec::new();
+                for a in VoxelGrid::<T>::points(*block) {
+                    let v_a = value(a);
+                    for axis in 0..3 {
+                        let mut b = a;
+                        b[axis] += 1;
+                        let v_b = value(b);
+                        if (v_a > iso) != (v_b > iso) {
+                            let p = vertex_interpret((a, v_a), (b, v_b), min_vector, baselen, iso, &self.clip);
+                            vertices.push((voxel::edge_key(a, axis), p));
+                        }
+                    }
+                }
+                vertices
+            })
+            .collect();
+
+        let mut first = 0;
+        let mut indices: HashMap<u64, HashMap<u64, usize>> = HashMap::new();
+        for (block, vertices) in blocks.iter().zip(&vertices) {
+            let index = vertices.iter().enumerate().map(|(i, (key, _))| (*key, first + i)).collect();
+            indices.insert(*block, index);
+            first += vertices.len();
+        }
 
-            if EDGE_TABLE[cubeindex] & 1 != 0 {
-                vertlist[0] = vertex_interpret(
-                    grid_p[0].clone(),
-                    grid_p[1].clone(),
-                    min_vector.clone(),
-                    baselen,
-                    valuemap,
-                    edgepos,
-                    edgemap,
-                ) as isize;
-            }
-            if EDGE_TABLE[cubeindex] & 2 != 0 {
-                vertlist[1] = vertex_interpret(
-                    grid_p[1].clone(),
-                    grid_p[2].clone(),
-                    min_vector.clone(),
-                    baselen,
-                    valuemap,
-                    edgepos,
-                    edgemap,
-                ) as isize;
-            }
-            if EDGE_TABLE[cubeindex] & 4 != 0 {
-                vertlist[2] = vertex_interpret(
-                    grid_p[3].clone(),
-                    grid_p[2].clone(),
-                    min_vector.clone(),
-                    baselen,
-                    valuemap,
-                    edgepos,
-                    edgemap,
-                ) as isize;
-            }
-            if EDGE_TABLE[cubeindex] & 8 != 0 {
-                vertlist[3] = vertex_interpret(
-                    grid_p[0].clone(),
-                    grid_p[3].clone(),
-                    min_vector.clone(),
-                    baselen,
-                    valuemap,
-                    edgepos,
-                    edgemap,
-                ) as isize;
-            }
-            if EDGE_TABLE[cubeindex] & 16 != 0 {
-                vertlist[4] = vertex_interpret(
-                    grid_p[4].clone(),
-                    grid_p[5].clone(),
-                    min_vector.clone(),
-                    baselen,
-                    valuemap,
-                    edgepos,
-                    edgemap,
-                ) as isize;
-            }
-            if EDGE_TABLE[cubeindex] & 32 != 0 {
-                vertlist[5] = vertex_interpret(
-                    grid_p[5].clone(),
-                    grid_p[6].clone(),
-                    min_vector.clone(),
-                    baselen,
-                    valuemap,
-                    edgepos,
-                    edgemap,
-                ) as isize;
-            }
-            if EDGE_TABLE[cubeindex] & 64 != 0 {
-                vertlist[6] = vertex_interpret(
-                    grid_p[7].clone(),
-                    grid_p[6].clone(),
-                    min_vector.clone(),
-                    baselen,
-                    valuemap,
-                    edgepos,
-                    edgemap,
-                ) as isize;
-            }
-            if EDGE_TABLE[cubeindex] & 128 != 0 {
-                vertlist[7] = vertex_interpret(
-                    grid_p[4].clone(),
-                    grid_p[7].clone(),
-                    min_vector.clone(),
-                    baselen,
-                    valuemap,
-                    edgepos,
-                    edgemap,
-                ) as isize;
-            }
-            if EDGE_TABLE[cubeindex] & 256 != 0 {
-                vertlist[8] = vertex_interpret(
-                    grid_p[0].clone(),
-                    grid_p[4].clone(),
-                    min_vector.clone(),
-                    baselen,
-                    valuemap,
-                    edgepos,
-                    edgemap,
-                ) as isize;
-            }
-            if EDGE_TABLE[cubeindex] & 512 != 0 {
-                vertlist[9] = vertex_interpret(
-                    grid_p[1].clone(),
-                    grid_p[5].clone(),
-                    min_vector.clone(),
-                    baselen,
-                    valuemap,
-                    edgepos,
-                    edgemap,
-                ) as isize;
-            }
-            if EDGE_TABLE[cubeindex] & 1024 != 0 {
-                vertlist[10] = vertex_interpret(
-                    grid_p[2].clone(),
-                    grid_p[6].clone(),
-                    min_vector.clone(),
-                    baselen,
-                    valuemap,
-                    edgepos,
-                    edgemap,
-                ) as isize;
-            }
-            if EDGE_TABLE[cubeindex] & 2048 != 0 {
-                vertlist[11] = vertex_interpret(
-                    grid_p[3].clone(),
-                    grid_p[7].clone(),
-                    min_vector.clone(),
-                    baselen,
-                    valuemap,
-                    edgepos,
-                    edgemap,
-                ) as isize;
-            }
+        let triangles: Vec<Vec<[usize; 3]>> = blocks
+            .par_iter()
+            .map(|block| {
+                let values = field.padded(*block);
+                let origin = voxel::unpack(*block).map(|c| c * BLOCK);
+                let value = |p: [usize; 3]| values[voxel::padded_offset(std::array::from_fn(|k| p[k] - origin[k]))];
+
+                let mut triangles = Vec::new();
+                for [grid_x, grid_y, grid_z] in VoxelGrid::<T>::points(*block) {
+                    let corners: [[usize; 3]; 8] =
+                        std::array::from_fn(|ty| [grid_x + DX[ty], grid_y + DY[ty], grid_z + DZ[ty]]);
+
+                    let mut cubeindex = 0;
+                    for (ty, corner) in corners.iter().enumerate() {
+                        if value(*corner) > iso {
+                            cubeindex |= 1 << ty;
+                        }
+                    }
+                    if EDGE_TABLE[cubeindex] == 0 {
+                        continue;
+                    }
 
-            let mut i = 0;
-            while TRI_TABLE[cubeindex][i] != -1 {
-                let current = TRI_TABLE[cubeindex];
-                matrix.push((vertlist[current[i] as usize], vertlist[current[i+1] as usize], vertlist[current[i+2] as usize]));
-                i += 3;
+                    let vertex = |edge: i64| {
+                        let (a, b) = EDGE_CORNERS[edge as usize];
+                        let (a, b) = (corners[a], corners[b]);
+                        let axis = (0..3).find(|k| a[*k] != b[*k]).unwrap();
+                        let owner = voxel::pack(a.map(|c| c / BLOCK));
+                        indices[&owner][&voxel::edge_key(a, axis)]
+                    };
+                    let mut i = 0;
+                    while TRI_TABLE[cubeindex][i] != -1 {
+                        let current = TRI_TABLE[cubeindex];
+                        triangles.push([vertex(current[i]), vertex(current[i + 1]), vertex(current[i + 2])]);
+                        i += 3;
+                    }
+                }
+                triangles
+            })
+            .collect();
+
+        let positions = vertices.into_iter().flatten().map(|(_, p)| p).collect();
+        (positions, triangles.into_iter().flatten().collect())
+    }
+
+    // Particle densities, velocities and vorticities at the vertices, kernel
+    // weighted averages under the reconstruction's own kernels. Velocities
+    // and vorticities are left out when the frame doesn't carry velocities.
+    fn interpolate(&self, mesh: &mut Mesh) {
+        let attributes = self.attributes;
+        if !(attributes.velocity || attributes.density || attributes.vorticity) {
+            return;
+        }
+        let input = &self.input;
+        let velocities: Option<Vec<Vector>> = (attributes.velocity || attributes.vorticity)
+            .then(|| input.iter().map(|p| p.velocity.map(|v| v.cast())).collect())
+            .flatten();
+        let vorticities = velocities.as_deref().filter(|_| attributes.vorticity).map(|v| self.vorticities(v));
+
+        // Every particle in the cells its kernel reaches, one lookup per vertex
+        let method = self.method();
+        let size = 2.0 * self.h.as_f64();
+        let cell = |p: Vector| [p.get_x(), p.get_y(), p.get_z()].map(|x| (x / size).floor() as i64);
+        let mut grid: HashMap<[i64; 3], Vec<usize>> = HashMap::new();
+        for i in 0..input.len() {
+            let [low, high] = method.support(i).map(|corner| cell(corner.cast()));
+            for x in low[0]..=high[0] {
+                for y in low[1]..=high[1] {
+                    for z in low[2]..=high[2] {
+                        grid.entry([x, y, z]).or_default().push(i);
+                    }
+                }
             }
         }
 
+        let zero = Vector::new(0.0, 0.0, 0.0);
+        let samples: Vec<(f64, Vector, Vector)> = mesh
+            .positions
+            .par_iter()
+            .map(|p| {
+                let mut sum = 0.0;
+                let (mut density, mut velocity, mut vorticity) = (0.0, zero, zero);
+                for i in grid.get(&cell(*p)).into_iter().flatten() {
+                    let w = method.kernel(*i, p.cast()).as_f64();
+                    if w <= 0.0 {
+                        continue;
+                    }
+                    sum += w;
+                    density += w * input[*i].density.as_f64();
+                    if let Some(velocities) = &velocities {
+                        velocity = velocity.addv(velocities[*i].mulf(w));
+                    }
+                    if let Some(vorticities) = &vorticities {
+                        vorticity = vorticity.addv(vorticities[*i].mulf(w));
+                    }
+                }
+                if sum > 0.0 {
+                    return (density / sum, velocity.divf(sum), vorticity.divf(sum));
+                }
+                (density, velocity, vorticity)
+            })
+            .collect();
 
-        fs::create_dir_all("./render").unwrap();
-        //Create output.obj if don't exist
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(format!("./render/water_{}", self.frame))
-            .unwrap();
+        if attributes.density {
+            mesh.densities = Some(samples.iter().map(|s| s.0).collect());
+        }
+        if attributes.velocity && velocities.is_some() {
+            mesh.velocities = Some(samples.iter().map(|s| s.1).collect());
+        }
+        if vorticities.is_some() {
+            mesh.vorticities = Some(samples.iter().map(|s| s.2).collect());
+        }
+    }
 
-        if let Err(e) = writeln!(file, "Shape \"trianglemesh\"  \"integer indices\" [") {
-            eprintln!("Couldn't write to file: {}", e);
+    // SPH curl of the velocities at every particle,
+    // ω_i = Σ V_j (v_i - v_j) × ∇w_ij / c with c = Σ V_j (x_j - x_i) · ∇w_ij / 3
+    // normalizing the gradient. For the cubic falloff of `reconstruction::weight`
+    // ∇w_ij is r_ij (x_j - x_i) up to a constant, which c cancels.
+    fn vorticities(&self, velocities: &[Vector]) -> Vec<Vector> {
+        let radius = 2.0 * self.h.as_f64();
+        let mass = self.mass.as_f64();
+        let positions: Vec<Vector> = self.input.iter().map(|p| p.vector.cast()).collect();
+        let volumes: Vec<f64> = self
+            .input
+            .iter()
+            .map(|p| if p.density > T::zero() { mass / p.density.as_f64() } else { 0.0 })
+            .collect();
+
+        let cell = |p: Vector| [p.get_x(), p.get_y(), p.get_z()].map(|x| (x / radius).floor() as i64);
+        let mut grid: HashMap<[i64; 3], Vec<usize>> = HashMap::new();
+        for (i, p) in positions.iter().enumerate() {
+            grid.entry(cell(*p)).or_default().push(i);
         }
 
-            /*let to_append = format!("{} {} {} ", input.0, input.1, input.2);
-            data.push_str(&to_append);*/
-        
-        for input in matrix {
-            if let Err(e) = writeln!(file, "{} {} {}", input.0, input.1, input.2) {
-                eprintln!("Couldn't write to file: {}", e);
-            }
+        (0..positions.len())
+            .into_par_iter()
+            .map(|i| {
+                let [cx, cy, cz] = cell(positions[i]);
+                let mut curl = Vector::new(0.0, 0.0, 0.0);
+                let mut c = 0.0;
+                for x in cx - 1..=cx + 1 {
+                    for y in cy - 1..=cy + 1 {
+                        for z in cz - 1..=cz + 1 {
+                            for j in grid.get(&[x, y, z]).into_iter().flatten() {
+                                let d = positions[*j].subv(positions[i]);
+                                let r = d.square_size().sqrt();
+                                if r >= radius || r == 0.0 {
+                                    continue;
+                                }
+                                let gradient = d.mulf(r * volumes[*j]);
+                                curl = curl.addv(velocities[i].subv(velocities[*j]).cross(gradient));
+                                c += d.dot(gradient) / 3.0;
+                            }
+                        }
+                    }
+                }
+                if c > 0.0 {
+                    return curl.divf(c);
+                }
+                curl
+            })
+            .collect()
+    }
+}
+
+// LuxRender trianglemesh shapes, included by the scene files. Always written
+// with Y and Z swapped, the winding is kept as generated.
+pub struct LuxWriter(pub MeshOutput);
+
+impl MeshWriter for LuxWriter {
+    fn write(&self, frame: usize, mesh: &Mesh) -> io::Result<()> {
+        let output = &self.0;
+        let mut file = BufWriter::new(File::create(output.path(frame, "")?)?);
+
+        writeln!(file, "Shape \"trianglemesh\"  \"integer indices\" [")?;
+        for [a, b, c] in &mesh.triangles {
+            writeln!(file, "{} {} {}", a, b, c)?;
         }
-        if let Err(e) = writeln!(file, "]  \"point P\" [") {
-            eprintln!("Couldn't write to file: {}", e);
+        writeln!(file, "]  \"point P\" [")?;
+        for p in &mesh.positions {
+            let [x, y, z] = output.axes.point(*p);
+            writeln!(file, "{} {} {} ", x, y, z)?;
         }
-        
-        for p in &self.edgepos {
-            if let Err(e) = writeln!(file, "{} {} {} ", p.get_x(), p.get_z(), p.get_y()) {
-                eprintln!("Couldn't write to file: {}", e);
+        if output.normals {
+            writeln!(file, "]  \"normal N\" [")?;
+            for n in mesh.normals() {
+                let [x, y, z] = output.axes.direction(n);
+                writeln!(file, "{} {} {} ", x, y, z)?;
+            }
+        }
+        // Custom parameters, LuxRender warns about them and carries on
+        for (name, vectors) in mesh.vectors(&output.axes) {
+            writeln!(file, "]  \"vector {}\" [", name)?;
+            for [x, y, z] in vectors {
+                writeln!(file, "{} {} {} ", x, y, z)?;
             }
         }
-        if let Err(e) = writeln!(file, "]") {
-            eprintln!("Couldn't write to file: {}", e);
+        for (name, scalars) in mesh.scalars(&output.axes) {
+            writeln!(file, "]  \"float {}\" [", name)?;
+            for value in scalars {
+                writeln!(file, "{} ", value)?;
+            }
         }
-
-        //clear
-        self.edgepos = Vec::new();
-        self.edgemap = HashMap::new();
-        self.valuemap = HashMap::new();
-        self.gridset = BTreeSet::new();
-
+        writeln!(file, "]")?;
+        file.flush()
     }
 }
@@ -2,124 +2,384 @@ mod vectors;
 mod sph;
 mod luxrender;
 mod eigen_value;
+mod whitewater;
+mod matrix;
+mod granular;
+mod elastic;
+mod forces;
+mod scene;
+mod real;
+mod stream;
+mod cache;
+mod checkpoint;
+mod vtk;
+mod export;
+mod points;
+mod mesh;
+mod gltf;
+mod voxel;
+mod reconstruction;
+mod surface_nets;
+mod postprocess;
+mod clip;
+mod volume;
 //https://elrnv.com/cs888/cs888proj.pdf
-use std::{time::Instant, env, sync::{Arc, atomic::{AtomicUsize, Ordering}, RwLock}};
-use rayon::prelude::{IntoParallelIterator, ParallelIterator};
-use std::io::Write;
+use std::{time::Instant, env};
 use prgrs::Prgrs;
 use vectors::Vector;
+use real::Real;
 
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-pub struct DensityPosition {
-    pub vector: Vector,
-    pub density: f64,
-    pub timestamp: f64
+#[serde(bound = "")]
+pub struct DensityPosition<T: Real = f64> {
+    #[serde(default)]
+    pub id: u64,
+    pub vector: Vector<T>,
+    pub density: T,
+    pub timestamp: T,
+
+    // Optional attributes, chosen at recording time (see stream::Attributes)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub velocity: Option<Vector<T>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pressure: Option<T>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phase: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub age: Option<T>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<T>,
 }
 
 // static TOTAL: usize = 500;
 static TIME: usize = 500;
 static DT: f64 = 1.0/144.0;//1.0/144.0;
 
-impl DensityPosition {
-    pub fn new(vector: Vector, density: f64, timestamp: f64) -> Self{
+impl<T: Real> DensityPosition<T> {
+    pub fn new(id: u64, vector: Vector<T>, density: T, timestamp: T) -> Self{
         Self{
+            id,
             vector,
             density,
-            timestamp
+            timestamp,
+            velocity: None,
+            pressure: None,
+            phase: None,
+            age: None,
+            temperature: None,
         }
     }
 }
 
 use three_d::*;
 
-use crate::{sph::SPH, luxrender::Renderer};
+use crate::{sph::{SPH, DensityFilter, Dimension, Material}, whitewater::Whitewater, stream::{Attributes, FrameStream, ViewerSink, DiskSink, MeshSink, WhitewaterSink}, cache::{attribute_bits, CacheReader, CacheSink, Header, Encoding, Compression}, granular::GranularParams, elastic::ElasticParams, scene::Scene, checkpoint::Checkpoint, vtk::{VtkFormat, VtkWriter}, export::{CoordinateSystem, ExportSink, ParticleWriter, Particles}, points::{GeoWriter, PlyWriter}, mesh::{MeshAttributes, MeshOutput, MeshWriter, ObjWriter, StlWriter}, luxrender::LuxWriter, gltf::GltfWriter, reconstruction::{Mesher, Method, ReconstructionConfig}, clip::Clip, volume::{MitsubaWriter, NrrdWriter, SparseWriter, VolumeWriter}};
+
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == flag)
+        .and_then(|i| args.get(i + 1))
+        .map(|v| v.as_str())
+}
 
 pub fn main() {
-    let bounds = Vector::new(50.0, 50.0, 50.0);
-    let from = Vector::new(0.0,0.0,0.0);
-    let to = Vector::new(25.0, 25.0, 25.0);
+    let args: Vec<String> = env::args().collect();
+
+    if args.iter().any(|a| a == "--f32") {
+        run::<f32>(args);
+    } else {
+        run::<f64>(args);
+    }
+}
 
-    let mut sph = SPH::new(bounds, DT);
+fn run<T: Real>(args: Vec<String>) {
+    let bounds = Vector::new(50.0, 50.0, 50.0).cast();
+    let from = Vector::new(0.0,0.0,0.0).cast();
+    let to = Vector::new(25.0, 25.0, 25.0).cast();
+
+    let mut sph = if args.iter().any(|a| a == "--2d") {
+        SPH::<T>::with_dimension(bounds, T::of(DT), Dimension::Two)
+    } else {
+        SPH::new(bounds, T::of(DT))
+    };
     sph.add_particle(&from, &to);
+
+    // ./sph [true] [--reinit <steps>] [--mls] [--shifting] [--whitewater live|timeline] [--sand dry|wet] [--jelly] [--scene <file.json>] [--2d] [--f32] [--record <dir>] [--cache <file.sphc> [--quantize|--cache-f32] [--compress]] [--replay <file.sphc>] [--checkpoint <dir> [--checkpoint-every <steps>]] [--resume <checkpoint.bin>] [--vtk <dir> [--vtk-legacy]] [--geo <dir>] [--ply <dir>] [--axes z-up|y-up|swap-yz] [--units <scale>] [--attributes velocity,pressure,acceleration,phase,age,temperature|all|none] [--mesh lux,obj,ply,stl|none] [--mesh-dir <dir>] [--mesh-pattern <name_{frame}>] [--mesh-normals] [--mesh-velocities] [--mesh-attributes velocity,density,vorticity,curvature|all|none] [--gltf <file.gltf|file.glb>] [--volume vol,nrrd,sparse] [--volume-dir <dir>] [--volume-pattern <name_{frame}>] [--reconstruction preview|production] [--iso-level <v>] [--voxel-size <v>] [--kernel-scale <v>] [--min-neighbors <n>] [--isolated-radius <v>] [--max-anisotropy <v>] [--padding <v>] [--surface-method anisotropic|isotropic|zhu-bridson|solenthaler] [--mesher marching-cubes|surface-nets] [--volume-fraction <v>] [--kernel-radius <v>] [--particle-radius <v>] [--t-low <v>] [--t-high <v>] [--weld <voxels>] [--smooth <iterations> [--smooth-lambda <v>] [--smooth-mu <v>]] [--decimate <triangles>] [--temporal <frames> [--temporal-falloff <v>]] [--no-clip]
+    if let Some(sand) = flag_value(&args, "--sand") {
+        let params = match sand {
+            "wet" => GranularParams::wet_sand(),
+            _ => GranularParams::dry_sand(),
+        };
+        let phase = sph.add_material(Material::Granular(params));
+        sph.add_phase_particle(&Vector::new(30.0, 0.0, 0.0).cast(), &Vector::new(45.0, 20.0, 15.0).cast(), phase);
+    }
+    if args.iter().any(|a| a == "--jelly") {
+        let phase = sph.add_material(Material::Elastic(ElasticParams::jelly()));
+        sph.add_phase_particle(&Vector::new(8.0, 8.0, 32.0).cast(), &Vector::new(16.0, 16.0, 40.0).cast(), phase);
+    }
     sph.construct_grid();
 
-    let mut t:f64 = 0.0;
-    let mut end:Vec<DensityPosition>= Vec::new();
-    
+    let scene = flag_value(&args, "--scene").map(|path| Scene::load(path).expect("Could not read the scene file"));
+    if let Some(scene) = &scene {
+        scene.apply(&mut sph);
+    }
+
+    if let Some(steps) = flag_value(&args, "--reinit") {
+        sph.reinit_every = steps.parse().expect("--reinit expects a step count");
+    }
+    if args.iter().any(|a| a == "--mls") {
+        sph.reinit_filter = DensityFilter::Mls;
+    }
+    sph.shifting = args.iter().any(|a| a == "--shifting");
+
+    let render = args.len() > 1 && args[1] == "true";
+    let whitewater_mode = flag_value(&args, "--whitewater");
+    let mut whitewater = Whitewater::new(sph.h.as_f64(), DT, sph.bounds().cast());
+
+    let mut first_step = 0;
+    let mut t = T::zero();
+    if let Some(path) = flag_value(&args, "--resume") {
+        let checkpoint = Checkpoint::<T>::load(path).expect("Could not read the checkpoint");
+        first_step = checkpoint.step;
+        t = checkpoint.t;
+        sph = checkpoint.sph;
+        whitewater = checkpoint.whitewater;
+        // Forces are not part of the checkpoint
+        if let Some(scene) = &scene {
+            scene.apply(&mut sph);
+        }
+    }
+    let checkpoint_dir = flag_value(&args, "--checkpoint");
+    let checkpoint_every: usize = flag_value(&args, "--checkpoint-every")
+        .map_or(100, |steps| steps.parse().expect("--checkpoint-every expects a step count"));
+
+    // Replaying a cache skips the simulation, the frames go through the same writers
+    let mut replay = flag_value(&args, "--replay").map(|path| CacheReader::open(path).expect("Could not open the particle cache"));
+    let (h, mass) = match &replay {
+        Some(cache) => (T::of(cache.header().h), T::of(cache.header().mass)),
+        None => (sph.h, sph.mass),
+    };
+
+    // Recorded and exported on top of id, position and density
+    let attributes = flag_value(&args, "--attributes").map_or(Attributes::default(), |list| {
+        Attributes::parse(list).expect("--attributes expects velocity, pressure, acceleration, phase, age, temperature, all or none")
+    });
+
+    // At most 4 frames wait in front of each writer
+    let mut stream = FrameStream::new(4);
+    stream.skip_to(first_step);
+    let viewer = match &replay {
+        Some(cache) => ViewerSink::for_run(cache.header().particles as usize, cache.len()),
+        None => ViewerSink::for_run(sph.positions.len(), TIME.saturating_sub(first_step)),
+    };
+    let frames = viewer.frames();
+    stream.add_sink(viewer);
+    if let Some(dir) = flag_value(&args, "--record") {
+        stream.add_sink(DiskSink::new(dir));
+    }
+    if let Some(path) = flag_value(&args, "--cache") {
+        let header = Header {
+            particles: sph.positions.len() as u32,
+            attributes: attribute_bits(&attributes),
+            // Full precision unless asked otherwise, f64 runs stay f64
+            encoding: if args.iter().any(|a| a == "--quantize") {
+                Encoding::Quantized
+            } else if args.iter().any(|a| a == "--cache-f32") || std::mem::size_of::<T>() < std::mem::size_of::<f64>() {
+                Encoding::F32
+            } else {
+                Encoding::F64
+            },
+            compression: if args.iter().any(|a| a == "--compress") { Compression::Deflate } else { Compression::None },
+            dt: DT,
+            h: sph.h.as_f64(),
+            mass: sph.mass.as_f64(),
+            min: Vector::new(0.0, 0.0, 0.0),
+            max: sph.bounds().cast(),
+            frames: 0,
+        };
+        stream.add_sink(CacheSink::new(path, header).expect("Could not create the particle cache"));
+    }
+    // Particle exports, written from the solver state while simulating
+    let mut axes = flag_value(&args, "--axes")
+        .map_or(CoordinateSystem::z_up(), |name| CoordinateSystem::parse(name).expect("--axes expects z-up, y-up or swap-yz"));
+    if let Some(scale) = flag_value(&args, "--units") {
+        axes = axes.with_scale(scale.parse().expect("--units expects a scale factor"));
+    }
+    let mut exporters: Vec<Box<dyn ParticleWriter<T>>> = Vec::new();
+    if let Some(dir) = flag_value(&args, "--vtk") {
+        let format = if args.iter().any(|a| a == "--vtk-legacy") { VtkFormat::Legacy } else { VtkFormat::Xml };
+        exporters.push(Box::new(VtkWriter::new(dir, format, first_step).expect("Could not create the VTK directory")));
+    }
+    if let Some(dir) = flag_value(&args, "--geo") {
+        exporters.push(Box::new(GeoWriter::new(dir, "particles", axes).expect("Could not create the .geo directory")));
+    }
+    if let Some(dir) = flag_value(&args, "--ply") {
+        exporters.push(Box::new(PlyWriter::new(dir, "particles", axes).expect("Could not create the PLY directory")));
+    }
+    // Without the solver state, velocities and accelerations come from the timeline
+    if replay.is_some() && !exporters.is_empty() {
+        let (kp, rest_density) = sph.equation_of_state();
+        stream.add_sink(ExportSink::new(std::mem::take(&mut exporters), attributes, kp, rest_density));
+    }
+    if render {
+        // Surface meshes, LuxRender shapes in ./render by default
+        let mut output = MeshOutput::new(
+            flag_value(&args, "--mesh-dir").unwrap_or("./render"),
+            flag_value(&args, "--mesh-pattern").unwrap_or("water_{frame}"),
+        );
+        output.normals = args.iter().any(|a| a == "--mesh-normals");
+        output.axes = axes;
+        // Computed on the vertices, --mesh-velocities is short for --mesh-attributes velocity
+        let mut mesh_attributes = flag_value(&args, "--mesh-attributes").map_or(MeshAttributes::none(), |list| {
+            MeshAttributes::parse(list).expect("--mesh-attributes expects velocity, density, vorticity, curvature, all or none")
+        });
+        mesh_attributes.velocity |= args.iter().any(|a| a == "--mesh-velocities");
+        let mut writers: Vec<Box<dyn MeshWriter>> = Vec::new();
+        for format in flag_value(&args, "--mesh").unwrap_or("lux").split(',') {
+            match format {
+                "lux" => writers.push(Box::new(LuxWriter(MeshOutput { axes: CoordinateSystem::swap_yz(), ..output.clone() }))),
+                "obj" => writers.push(Box::new(ObjWriter(output.clone()))),
+                "ply" => writers.push(Box::new(mesh::PlyWriter(output.clone()))),
+                "stl" => writers.push(Box::new(StlWriter(output.clone()))),
+                "none" => {}
+                _ => panic!("--mesh expects lux, obj, ply, stl or none"),
+            }
+        }
+        // Every frame in one animated asset, one mesh frame per step
+        if let Some(path) = flag_value(&args, "--gltf") {
+            writers.push(Box::new(GltfWriter::new(path, DT, &output)));
+        }
+        // The splatted field as a volume, next to or instead of (--mesh none) the surface
+        let mut volumes: Vec<Box<dyn VolumeWriter>> = Vec::new();
+        if let Some(formats) = flag_value(&args, "--volume") {
+            let output = MeshOutput {
+                axes,
+                ..MeshOutput::new(
+                    flag_value(&args, "--volume-dir").unwrap_or("./volume"),
+                    flag_value(&args, "--volume-pattern").unwrap_or("density_{frame}"),
+                )
+            };
+            for format in formats.split(',') {
+                match format {
+                    "vol" => volumes.push(Box::new(MitsubaWriter(output.clone()))),
+                    "nrrd" => volumes.push(Box::new(NrrdWriter(output.clone()))),
+                    "sparse" => volumes.push(Box::new(SparseWriter(output.clone()))),
+                    _ => panic!("--volume expects vol, nrrd or sparse"),
+                }
+            }
+        }
+        // Reconstruction settings: preset, then the scene file, then the flags
+        let number = |flag: &str| {
+            flag_value(&args, flag).map(|value| value.parse::<f64>().unwrap_or_else(|_| panic!("{} expects a number", flag)))
+        };
+        let flags = ReconstructionConfig {
+            preset: flag_value(&args, "--reconstruction").map(str::to_string),
+            method: flag_value(&args, "--surface-method").map(|name| {
+                Method::parse(name).expect("--surface-method expects anisotropic, isotropic, zhu-bridson or solenthaler")
+            }),
+            mesher: flag_value(&args, "--mesher")
+                .map(|name| Mesher::parse(name).expect("--mesher expects marching-cubes or surface-nets")),
+            iso_level: number("--iso-level"),
+            volume_fraction: number("--volume-fraction"),
+            voxel_size: number("--voxel-size"),
+            kernel_scale: number("--kernel-scale"),
+            min_neighbors: flag_value(&args, "--min-neighbors").map(|n| n.parse().expect("--min-neighbors expects a count")),
+            isolated_radius: number("--isolated-radius"),
+            max_anisotropy: number("--max-anisotropy"),
+            padding: number("--padding"),
+            kernel_radius: number("--kernel-radius"),
+            particle_radius: number("--particle-radius"),
+            t_low: number("--t-low"),
+            t_high: number("--t-high"),
+            weld_distance: number("--weld"),
+            smooth_iterations: flag_value(&args, "--smooth").map(|n| n.parse().expect("--smooth expects a count")),
+            smooth_lambda: number("--smooth-lambda"),
+            smooth_mu: number("--smooth-mu"),
+            target_triangles: flag_value(&args, "--decimate").map(|n| n.parse().expect("--decimate expects a count")),
+            temporal_frames: flag_value(&args, "--temporal").map(|n| n.parse().expect("--temporal expects a frame count")),
+            temporal_falloff: number("--temporal-falloff"),
+            clip: args.iter().any(|a| a == "--no-clip").then_some(false),
+        };
+        let domain = match &replay {
+            Some(cache) => cache.header().max.subv(cache.header().min),
+            None => sph.bounds().cast(),
+        };
+        let params = scene
+            .as_ref()
+            .map_or_else(ReconstructionConfig::default, |scene| scene.reconstruction.clone())
+            .overridden_by(flags)
+            .params(domain, h.as_f64())
+            .unwrap_or_else(|e| panic!("Invalid reconstruction settings: {}", e));
+        // The fluid stays in the simulation box and out of the scene's colliders
+        let clip = if params.clip {
+            let colliders = scene.as_ref().map(|scene| scene.colliders.clone()).unwrap_or_default();
+            Clip::new((Vector::new(0.0, 0.0, 0.0), sph.bounds().cast()), colliders)
+        } else {
+            Clip::default()
+        };
+        stream.add_sink(MeshSink::new(rayon::current_num_threads(), 199, (h, mass), params, clip, (writers, volumes), mesh_attributes));
+        if whitewater_mode == Some("timeline") {
+            let sink = WhitewaterSink::new(whitewater.clone(), sph.gravity_field(), 199, sph.pradi.as_f64());
+            stream.add_sink(sink);
+        }
+    }
+
     //start tilme
     let start = Instant::now();
     
 
-    for _ in Prgrs::new(0..TIME, TIME) {
-
-        sph.density();
-
-        for i in 0..sph.positions.len(){
-            end.push(
-                DensityPosition::new(sph.positions[i].clone(), sph.densities[i], t)
-            )
+    if let Some(cache) = &mut replay {
+        for i in 0..cache.len() {
+            stream.push(cache.frame(i).expect("Could not read the particle cache"));
         }
-
-        sph.accelerate();
-        sph.update_position();
-
-        
-        t+=DT;
     }
 
-    let args: Vec<String> = env::args().collect();
-    let end = Arc::new(end);
-
-    if args.len() > 1 && args[1] == "true" {
-        let duration = start.elapsed();
-        println!("Time took to simulate: {:?}", duration);
-        
-        let max_thread = rayon::current_num_threads();
-        println!("{}", max_thread);
-
-        let mut pool = Vec::new();
-        for _ in  0..max_thread {
-            let prbt = Renderer::new(sph.positions.len(), 0, Arc::clone(&end), sph.h, sph.mass);
-            pool.push(Arc::new(RwLock::new(prbt)));
+    let steps = if replay.is_some() { 0 } else { TIME };
+    for step in Prgrs::new(first_step.min(steps)..steps, steps.saturating_sub(first_step)) {
+        if let Some(dir) = checkpoint_dir {
+            if step > first_step && step.checked_rem(checkpoint_every) == Some(0) {
+                if let Err(e) = checkpoint::save(dir, step, t, &sph, &whitewater) {
+                    eprintln!("Couldn't write checkpoint {}: {}", step, e);
+                }
+            }
         }
 
-        let start = Instant::now(); 
+        sph.density();
+        sph.reinitialize_density();
+        sph.shift_particles();
 
-        let actual = AtomicUsize::new(0);
-        (199..TIME).into_par_iter().for_each(| i |{
-            let mut prbt = Option::None;
+        stream.push(attributes.record(&sph, t));
 
-            let mut z = 0;
-            while let None = prbt {
-                if let Ok(pr) = pool[z].try_write() {
-                    prbt = Some(pr);
-                    break;
-                }
-                z += 1;
+        if whitewater_mode == Some("live") {
+            whitewater.step(&sph.positions, &sph.velocities, sph.gravity());
+            if render && step >= 199 {
+                luxrender::write_diffuse(step, &whitewater.particles, sph.pradi.as_f64());
+            }
+        }
 
-                if z > max_thread {
-                    z = 0;
+        sph.accelerate();
+        if !exporters.is_empty() {
+            let particles = Particles::from_sph(&sph, &attributes);
+            for writer in &mut exporters {
+                if let Err(e) = writer.write(step, t.as_f64(), &particles) {
+                    eprintln!("Couldn't export frame {}: {}", step, e);
                 }
-            
             }
-            
-
-            let mut prbt = prbt.unwrap();
-            prbt.set_frame(i);
-            prbt.generate();
-            actual.fetch_add(1, Ordering::SeqCst);
-            print!("{:?}/{}\r", actual, 500);
-            std::io::stdout().flush().unwrap();
-        });
+        }
+        sph.update_position();
 
         
-        let duration = start.elapsed();
-        println!("Time took to render: {:?}", duration);
-
+        t+=T::of(DT);
     }
 
+    stream.finish();
+    println!("Time took to simulate and write: {:?}", start.elapsed());
+
+    let frames = std::mem::take(&mut *frames.lock().unwrap());
+    let particles = frames.first().map_or(0, |frame| frame.len());
+
     let window = Window::new(WindowSettings {
         title: "Shapes!".to_string(),
         max_size: Some((1280, 720)),
@@ -140,7 +400,7 @@ pub fn main() {
 
     let mut control = OrbitControl::new(*camera.target(), 1.0, 100.0);
     let mut spheres: Vec<Gm<Mesh, PhysicalMaterial>> = Vec::new();
-    for _ in 0..sph.positions.len() {
+    for _ in 0..particles {
         spheres.push(
         Gm::new(
         Mesh::new(&context, &CpuMesh::sphere(16)),
@@ -234,17 +494,17 @@ pub fn main() {
         camera.set_viewport(frame_input.viewport);
         control.handle_events(&mut camera, &mut frame_input.events);
 
-        if k >= end.len() {
+        if k >= frames.len() {
             k = 0
         } 
-        for i in 0..sph.positions.len() {
-            let current = end[k+i];
+        for i in 0..particles {
+            let current = frames[k][i];
             spheres[i].set_transformation(
                 Mat4::from_translation(
                     vec3(
-                        current.vector.get_x() as f32, 
-                        current.vector.get_y() as f32, 
-                        current.vector.get_z() as f32 
+                        current[0], 
+                        current[1], 
+                        current[2] 
                     )
                 ) * Mat4::from_scale(0.1)
             );
@@ -262,7 +522,7 @@ pub fn main() {
             );
         // thread::sleep(time::Duration::from_millis((DT) as u64));
         if !paused && last_update.elapsed().as_secs_f64() > delay {
-            k += sph.positions.len();
+            k += 1;
             last_update = Instant::now();
         }
         
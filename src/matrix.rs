@@ -0,0 +1,150 @@
+use serde::{Serialize, Deserialize};
+
+use crate::{real::Real, vectors::Vector};
+
+impl<T: Real> Matrix<T> {
+    pub fn new(m: [[T; 3]; 3]) -> Self {
+        Self { m }
+    }
+
+    pub fn to_array(self) -> [[T; 3]; 3] {
+        self.m
+    }
+
+    pub fn cast<U: Real>(&self) -> Matrix<U> {
+        let mut m = [[U::zero(); 3]; 3];
+        for (l, row) in m.iter_mut().enumerate() {
+            for (n, value) in row.iter_mut().enumerate() {
+                *value = U::of(self.m[l][n].as_f64());
+            }
+        }
+        Matrix { m }
+    }
+    pub fn zero() -> Self {
+        Self { m: [[T::zero(); 3]; 3] }
+    }
+
+    pub fn identity() -> Self {
+        let (o, l) = (T::zero(), T::one());
+        Self {
+            m: [[l, o, o], [o, l, o], [o, o, l]],
+        }
+    }
+
+    // a * b^T
+    pub fn outer(a: Vector<T>, b: Vector<T>) -> Self {
+        let mut m = [[T::zero(); 3]; 3];
+        for (l, row) in m.iter_mut().enumerate() {
+            for (n, value) in row.iter_mut().enumerate() {
+                *value = a.get(l) * b.get(n);
+            }
+        }
+        Self { m }
+    }
+
+    pub fn trace(&self) -> T {
+        self.m[0][0] + self.m[1][1] + self.m[2][2]
+    }
+
+    pub fn transpose(&self) -> Self {
+        let mut m = [[T::zero(); 3]; 3];
+        for (l, row) in m.iter_mut().enumerate() {
+            for (n, value) in row.iter_mut().enumerate() {
+                *value = self.m[n][l];
+            }
+        }
+        Self { m }
+    }
+
+    // Frobenius inner product a:b
+    pub fn double_dot(&self, other: Self) -> T {
+        let mut sum = T::zero();
+        for l in 0..3 {
+            for n in 0..3 {
+                sum += self.m[l][n] * other.m[l][n];
+            }
+        }
+        sum
+    }
+
+    pub fn det(&self) -> T {
+        let m = &self.m;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    pub fn inverse(&self) -> Option<Self> {
+        let det = self.det();
+        if det.abs() < T::of(1e-12) {
+            return None;
+        }
+
+        let m = &self.m;
+        let cofactor = |a: usize, b: usize, c: usize, d: usize| m[a][b] * m[c][d] - m[a][d] * m[c][b];
+        Some(
+            Self {
+                m: [
+                    [cofactor(1, 1, 2, 2), -cofactor(0, 1, 2, 2), cofactor(0, 1, 1, 2)],
+                    [-cofactor(1, 0, 2, 2), cofactor(0, 0, 2, 2), -cofactor(0, 0, 1, 2)],
+                    [cofactor(1, 0, 2, 1), -cofactor(0, 0, 2, 1), cofactor(0, 0, 1, 1)],
+                ],
+            }
+            .divf(det),
+        )
+    }
+
+    //Math operation:
+
+    pub fn addm(&self, other: Self) -> Self {
+        let mut m = self.m;
+        for (l, row) in m.iter_mut().enumerate() {
+            for (n, value) in row.iter_mut().enumerate() {
+                *value += other.m[l][n];
+            }
+        }
+        Self { m }
+    }
+
+    pub fn subm(&self, other: Self) -> Self {
+        self.addm(other.mulf(-T::one()))
+    }
+
+    pub fn mulm(&self, other: Self) -> Self {
+        let mut m = [[T::zero(); 3]; 3];
+        for (l, row) in m.iter_mut().enumerate() {
+            for (n, value) in row.iter_mut().enumerate() {
+                *value = (0..3).map(|k| self.m[l][k] * other.m[k][n]).sum();
+            }
+        }
+        Self { m }
+    }
+
+    pub fn mulv(&self, other: Vector<T>) -> Vector<T> {
+        Vector::new(
+            self.m[0][0] * other.get_x() + self.m[0][1] * other.get_y() + self.m[0][2] * other.get_z(),
+            self.m[1][0] * other.get_x() + self.m[1][1] * other.get_y() + self.m[1][2] * other.get_z(),
+            self.m[2][0] * other.get_x() + self.m[2][1] * other.get_y() + self.m[2][2] * other.get_z(),
+        )
+    }
+
+    pub fn mulf(&self, other: T) -> Self {
+        let mut m = self.m;
+        for row in m.iter_mut() {
+            for value in row.iter_mut() {
+                *value *= other;
+            }
+        }
+        Self { m }
+    }
+
+    pub fn divf(&self, other: T) -> Self {
+        self.mulf(T::one() / other)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Matrix<T: Real = f64> {
+    m: [[T; 3]; 3]
+}
@@ -0,0 +1,329 @@
+// Surface meshes produced by the Renderer and the writers that save them:
+// Wavefront OBJ, binary PLY and binary STL (LuxRender lives in luxrender.rs).
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+};
+
+use crate::{export::CoordinateSystem, vectors::Vector};
+
+// Vertex attributes the Renderer computes, see Mesh
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MeshAttributes {
+    pub velocity: bool,
+    pub density: bool,
+    pub vorticity: bool,
+    pub curvature: bool,
+}
+
+impl MeshAttributes {
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn all() -> Self {
+        Self {
+            velocity: true,
+            density: true,
+            vorticity: true,
+            curvature: true,
+        }
+    }
+
+    // Comma separated names, e.g. "velocity,curvature", or "all" / "none"
+    pub fn parse(list: &str) -> Option<Self> {
+        let mut attributes = Self::none();
+        for name in list.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match name {
+                "all" => attributes = Self::all(),
+                "none" => attributes = Self::none(),
+                "velocity" | "v" => attributes.velocity = true,
+                "density" => attributes.density = true,
+                "vorticity" => attributes.vorticity = true,
+                "curvature" => attributes.curvature = true,
+                _ => return None,
+            }
+        }
+        Some(attributes)
+    }
+}
+
+pub struct Mesh {
+    pub positions: Vec<Vector>,
+    pub triangles: Vec<[usize; 3]>,
+    // Interpolated from the particles with the reconstruction kernels.
+    // Velocities and vorticities need frames that carry velocities.
+    pub velocities: Option<Vec<Vector>>,
+    pub densities: Option<Vec<f64>>,
+    pub vorticities: Option<Vec<Vector>>,
+    // Mean curvature of the surface itself, positive where it is convex
+    pub curvatures: Option<Vec<f64>>,
+}
+
+impl Mesh {
+    pub fn new(positions: Vec<Vector>, triangles: Vec<[usize; 3]>) -> Self {
+        Self {
+            positions,
+            triangles,
+            velocities: None,
+            densities: None,
+            vorticities: None,
+            curvatures: None,
+        }
+    }
+
+    // Scalar vertex attributes by name, in the units of `axes`: curvatures
+    // are per length
+    pub fn scalars(&self, axes: &CoordinateSystem) -> Vec<(&'static str, Vec<f64>)> {
+        let mut scalars = Vec::new();
+        if let Some(densities) = &self.densities {
+            scalars.push(("density", densities.clone()));
+        }
+        if let Some(curvatures) = &self.curvatures {
+            scalars.push(("curvature", curvatures.iter().map(|c| c / axes.scale).collect()));
+        }
+        scalars
+    }
+
+    // Vector vertex attributes by name, in `axes`: velocities are scaled like
+    // the positions, vorticities are rates and flip with mirroring axes
+    pub fn vectors(&self, axes: &CoordinateSystem) -> Vec<(&'static str, Vec<[f64; 3]>)> {
+        let mut vectors = Vec::new();
+        if let Some(velocities) = &self.velocities {
+            vectors.push(("velocity", velocities.iter().map(|v| axes.point(*v)).collect()));
+        }
+        if let Some(vorticities) = &self.vorticities {
+            let sign = if axes.is_mirror() { -1.0 } else { 1.0 };
+            let convert = |w: &Vector| axes.direction(*w).map(|x| sign * x);
+            vectors.push(("vorticity", vorticities.iter().map(convert).collect()));
+        }
+        vectors
+    }
+
+    // Mean curvature at every vertex from the cotangent Laplacian,
+    // H = -(Δx · n) / 2 with Δx = Σ (cot α + cot β) (x_j - x_i) / 2A
+    // over the edges ij and A a third of the area around i
+    pub fn mean_curvatures(&self) -> Vec<f64> {
+        let mut laplacians = vec![Vector::new(0.0, 0.0, 0.0); self.positions.len()];
+        let mut areas = vec![0.0; self.positions.len()];
+        for triangle in &self.triangles {
+            let normal = face_normal(self, triangle);
+            let twice_area = normal.square_size().sqrt();
+            if twice_area == 0.0 {
+                continue;
+            }
+            for k in 0..3 {
+                let (i, j, o) = (triangle[k], triangle[(k + 1) % 3], triangle[(k + 2) % 3]);
+                areas[i] += twice_area / 6.0;
+                // Cotangent of the angle at o, bounded on slivers
+                let (u, v) = (self.positions[i].subv(self.positions[o]), self.positions[j].subv(self.positions[o]));
+                let cot = (u.dot(v) / twice_area).clamp(-COT_LIMIT, COT_LIMIT);
+                let edge = self.positions[j].subv(self.positions[i]).mulf(cot / 2.0);
+                laplacians[i] = laplacians[i].addv(edge);
+                laplacians[j] = laplacians[j].subv(edge);
+            }
+        }
+        let normals = self.normals();
+        (0..self.positions.len())
+            .map(|i| {
+                if areas[i] == 0.0 {
+                    return 0.0;
+                }
+                -laplacians[i].dot(normals[i]) / (2.0 * areas[i])
+            })
+            .collect()
+    }
+
+    // Area weighted vertex normals, following the triangle winding
+    pub fn normals(&self) -> Vec<Vector> {
+        let mut normals = vec![Vector::new(0.0, 0.0, 0.0); self.positions.len()];
+        for triangle in &self.triangles {
+            let normal = face_normal(self, triangle);
+            for i in triangle {
+                normals[*i] = normals[*i].addv(normal);
+            }
+        }
+        normals.into_iter().map(normalize).collect()
+    }
+}
+
+// Cotangents past it come from slivers, about one degree
+const COT_LIMIT: f64 = 57.0;
+
+// Not normalized, its length is twice the triangle area
+fn face_normal(mesh: &Mesh, [a, b, c]: &[usize; 3]) -> Vector {
+    let (a, b, c) = (mesh.positions[*a], mesh.positions[*b], mesh.positions[*c]);
+    b.subv(a).cross(c.subv(a))
+}
+
+fn normalize(v: Vector) -> Vector {
+    let size = v.square_size().sqrt();
+    if size > 0.0 {
+        return v.divf(size);
+    }
+    v
+}
+
+pub trait MeshWriter: Send + Sync {
+    fn write(&self, frame: usize, mesh: &Mesh) -> io::Result<()>;
+
+    // Called once every frame has been written
+    fn finish(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Where and what the writers output. `pattern` is the file name without its
+// extension, `{frame}` is replaced by the frame number.
+#[derive(Debug, Clone)]
+pub struct MeshOutput {
+    pub dir: String,
+    pub pattern: String,
+    pub normals: bool,
+    pub axes: CoordinateSystem,
+}
+
+impl MeshOutput {
+    pub fn new(dir: &str, pattern: &str) -> Self {
+        Self {
+            dir: dir.to_string(),
+            pattern: pattern.to_string(),
+            normals: false,
+            axes: CoordinateSystem::z_up(),
+        }
+    }
+
+    pub fn path(&self, frame: usize, extension: &str) -> io::Result<String> {
+        fs::create_dir_all(&self.dir)?;
+        let name = self.pattern.replace("{frame}", &frame.to_string());
+        if extension.is_empty() {
+            return Ok(format!("{}/{}", self.dir, name));
+        }
+        Ok(format!("{}/{}.{}", self.dir, name, extension))
+    }
+
+    // Mirroring axes flip the triangle winding, swap two corners to keep it
+    fn corners(&self, [a, b, c]: [usize; 3]) -> [usize; 3] {
+        if self.axes.is_mirror() {
+            return [a, c, b];
+        }
+        [a, b, c]
+    }
+}
+
+pub struct ObjWriter(pub MeshOutput);
+
+impl MeshWriter for ObjWriter {
+    fn write(&self, frame: usize, mesh: &Mesh) -> io::Result<()> {
+        let output = &self.0;
+        let mut file = BufWriter::new(File::create(output.path(frame, "obj")?)?);
+
+        writeln!(file, "# frame {}", frame)?;
+        for p in &mesh.positions {
+            let [x, y, z] = output.axes.point(*p);
+            writeln!(file, "v {} {} {}", x, y, z)?;
+        }
+        if output.normals {
+            for n in mesh.normals() {
+                let [x, y, z] = output.axes.direction(n);
+                writeln!(file, "vn {} {} {}", x, y, z)?;
+            }
+        }
+        // OBJ indices start at 1
+        for triangle in &mesh.triangles {
+            let [a, b, c] = output.corners(*triangle).map(|i| i + 1);
+            if output.normals {
+                writeln!(file, "f {}//{} {}//{} {}//{}", a, a, b, b, c, c)?;
+            } else {
+                writeln!(file, "f {} {} {}", a, b, c)?;
+            }
+        }
+        file.flush()
+    }
+}
+
+pub struct PlyWriter(pub MeshOutput);
+
+impl MeshWriter for PlyWriter {
+    fn write(&self, frame: usize, mesh: &Mesh) -> io::Result<()> {
+        let output = &self.0;
+        let mut file = BufWriter::new(File::create(output.path(frame, "ply")?)?);
+        let normals = output.normals.then(|| mesh.normals());
+        let scalars = mesh.scalars(&output.axes);
+        let vectors = mesh.vectors(&output.axes);
+
+        writeln!(file, "ply")?;
+        writeln!(file, "format binary_little_endian 1.0")?;
+        writeln!(file, "element vertex {}", mesh.positions.len())?;
+        let mut properties = vec!["x", "y", "z"];
+        if normals.is_some() {
+            properties.extend(["nx", "ny", "nz"]);
+        }
+        for (name, _) in &vectors {
+            properties.extend(match *name {
+                "velocity" => ["vx", "vy", "vz"],
+                _ => ["wx", "wy", "wz"],
+            });
+        }
+        properties.extend(scalars.iter().map(|(name, _)| *name));
+        for name in properties {
+            writeln!(file, "property float {}", name)?;
+        }
+        writeln!(file, "element face {}", mesh.triangles.len())?;
+        writeln!(file, "property list uchar uint vertex_indices")?;
+        writeln!(file, "end_header")?;
+
+        for i in 0..mesh.positions.len() {
+            let mut values = output.axes.point(mesh.positions[i]).to_vec();
+            if let Some(normals) = &normals {
+                values.extend(output.axes.direction(normals[i]));
+            }
+            for (_, vectors) in &vectors {
+                values.extend(vectors[i]);
+            }
+            for (_, scalars) in &scalars {
+                values.push(scalars[i]);
+            }
+            for value in values {
+                file.write_all(&(value as f32).to_le_bytes())?;
+            }
+        }
+        for triangle in &mesh.triangles {
+            file.write_all(&[3])?;
+            for i in output.corners(*triangle) {
+                file.write_all(&(i as u32).to_le_bytes())?;
+            }
+        }
+        file.flush()
+    }
+}
+
+// Binary STL only holds facets with their normal, vertex normals and
+// attributes are never written
+pub struct StlWriter(pub MeshOutput);
+
+impl MeshWriter for StlWriter {
+    fn write(&self, frame: usize, mesh: &Mesh) -> io::Result<()> {
+        let output = &self.0;
+        let mut file = BufWriter::new(File::create(output.path(frame, "stl")?)?);
+
+        let mut header = [0u8; 80];
+        let title = format!("fluid surface, frame {}", frame);
+        header[..title.len()].copy_from_slice(title.as_bytes());
+        file.write_all(&header)?;
+        file.write_all(&(mesh.triangles.len() as u32).to_le_bytes())?;
+
+        for triangle in &mesh.triangles {
+            let corners = output.corners(*triangle);
+            let normal = output.axes.direction(normalize(face_normal(mesh, triangle)));
+            let points = corners.map(|i| output.axes.point(mesh.positions[i]));
+            for value in normal.into_iter().chain(points.into_iter().flatten()) {
+                file.write_all(&(value as f32).to_le_bytes())?;
+            }
+            // Attribute byte count
+            file.write_all(&0u16.to_le_bytes())?;
+        }
+        file.flush()
+    }
+}
@@ -0,0 +1,149 @@
+// Point cloud exports for Houdini (ASCII .geo) and Blender (binary PLY), one
+// file per frame with a point attribute per exported attribute (`id`,
+// `density`, `v`, `age`, ...). Positions and vector attributes go through the
+// configured coordinate conversion.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+};
+
+use crate::{
+    export::{CoordinateSystem, ParticleWriter, Particles},
+    real::Real,
+};
+
+// Houdini's names for the standard attributes
+fn houdini_name(name: &str) -> &str {
+    match name {
+        "velocity" => "v",
+        "acceleration" => "accel",
+        _ => name,
+    }
+}
+
+// <dir>/<prefix>.<frame>.geo, the numbering Houdini's file SOP expects ($F)
+pub struct GeoWriter {
+    dir: String,
+    prefix: String,
+    axes: CoordinateSystem,
+}
+
+impl GeoWriter {
+    pub fn new(dir: &str, prefix: &str, axes: CoordinateSystem) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_string(),
+            prefix: prefix.to_string(),
+            axes,
+        })
+    }
+}
+
+impl<T: Real> ParticleWriter<T> for GeoWriter {
+    fn write(&mut self, index: usize, _time: f64, particles: &Particles<T>) -> io::Result<()> {
+        let path = format!("{}/{}.{}.geo", self.dir, self.prefix, index);
+        let mut file = BufWriter::new(File::create(path)?);
+        let n = particles.len();
+        let vectors = particles.vectors();
+        let scalars = particles.scalars();
+        let integers = particles.integers();
+
+        writeln!(file, "PGEOMETRY V5")?;
+        writeln!(file, "NPoints {} NPrims 0", n)?;
+        writeln!(file, "NPointGroups 0 NPrimGroups 0")?;
+        writeln!(
+            file,
+            "NPointAttrib {} NVertexAttrib 0 NPrimAttrib 0 NAttrib 0",
+            vectors.len() + scalars.len() + integers.len()
+        )?;
+        writeln!(file, "PointAttrib")?;
+        for (name, _) in &vectors {
+            writeln!(file, "{} 3 vector 0 0 0", houdini_name(name))?;
+        }
+        for (name, _) in &scalars {
+            writeln!(file, "{} 1 float 0", houdini_name(name))?;
+        }
+        for (name, _) in &integers {
+            writeln!(file, "{} 1 int 0", houdini_name(name))?;
+        }
+
+        for i in 0..n {
+            let [x, y, z] = self.axes.point(particles.positions[i]);
+            let mut values = Vec::new();
+            for (_, vector) in &vectors {
+                values.extend(self.axes.point(vector[i]).map(|v| v.to_string()));
+            }
+            values.extend(scalars.iter().map(|(_, scalar)| scalar[i].to_string()));
+            values.extend(integers.iter().map(|(_, integer)| integer[i].to_string()));
+            writeln!(file, "{} {} {} 1 ({})", x, y, z, values.join(" "))?;
+        }
+        writeln!(file, "beginExtra")?;
+        writeln!(file, "endExtra")?;
+        file.flush()
+    }
+}
+
+// <dir>/<prefix>_<frame>.ply, binary little endian vertices only
+pub struct PlyWriter {
+    dir: String,
+    prefix: String,
+    axes: CoordinateSystem,
+}
+
+impl PlyWriter {
+    pub fn new(dir: &str, prefix: &str, axes: CoordinateSystem) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_string(),
+            prefix: prefix.to_string(),
+            axes,
+        })
+    }
+}
+
+impl<T: Real> ParticleWriter<T> for PlyWriter {
+    fn write(&mut self, index: usize, _time: f64, particles: &Particles<T>) -> io::Result<()> {
+        let path = format!("{}/{}_{}.ply", self.dir, self.prefix, index);
+        let mut file = BufWriter::new(File::create(path)?);
+        let n = particles.len();
+        let vectors = particles.vectors();
+        let scalars = particles.scalars();
+        let integers = particles.integers();
+
+        writeln!(file, "ply")?;
+        writeln!(file, "format binary_little_endian 1.0")?;
+        writeln!(file, "element vertex {}", n)?;
+        for axis in ["x", "y", "z"] {
+            writeln!(file, "property float {}", axis)?;
+        }
+        // velocity => vx vy vz, acceleration => ax ay az
+        for (name, _) in &vectors {
+            for axis in ["x", "y", "z"] {
+                writeln!(file, "property float {}{}", &name[..1], axis)?;
+            }
+        }
+        for (name, _) in &scalars {
+            writeln!(file, "property float {}", name)?;
+        }
+        for (name, _) in &integers {
+            writeln!(file, "property uint {}", name)?;
+        }
+        writeln!(file, "end_header")?;
+
+        for i in 0..n {
+            let mut floats = self.axes.point(particles.positions[i]).to_vec();
+            for (_, vector) in &vectors {
+                floats.extend(self.axes.point(vector[i]));
+            }
+            floats.extend(scalars.iter().map(|(_, scalar)| scalar[i].as_f64()));
+            for value in floats {
+                file.write_all(&(value as f32).to_le_bytes())?;
+            }
+            for (_, integer) in &integers {
+                file.write_all(&(integer[i] as u32).to_le_bytes())?;
+            }
+        }
+        file.flush()
+    }
+}
@@ -0,0 +1,338 @@
+// Clean up of the polygonized surface, run by the Renderer before vertex
+// attributes are computed: vertex welding, Taubin smoothing and quadric error
+// decimation. Every step is off until its setting is given.
+
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+use crate::{matrix::Matrix, mesh::Mesh, reconstruction::ReconstructionParams, vectors::Vector};
+
+pub fn post_process(mesh: &mut Mesh, params: &ReconstructionParams) {
+    if params.weld_distance > 0.0 {
+        weld(mesh, params.weld_distance * params.voxel_size);
+    }
+    if params.smooth_iterations > 0 {
+        taubin(mesh, params.smooth_iterations, params.smooth_lambda, params.smooth_mu);
+    }
+    if params.target_triangles > 0 {
+        decimate(mesh, params.target_triangles);
+    }
+}
+
+// Merges the vertices closer than `distance` and drops the triangles that
+// collapse on the way
+pub fn weld(mesh: &mut Mesh, distance: f64) {
+    let cell = |p: Vector| [p.get_x(), p.get_y(), p.get_z()].map(|x| (x / distance).floor() as i64);
+    let mut grid: HashMap<[i64; 3], Vec<usize>> = HashMap::new();
+    let mut remap = Vec::with_capacity(mesh.positions.len());
+
+    for (i, p) in mesh.positions.iter().enumerate() {
+        let [x, y, z] = cell(*p);
+        let mut found = None;
+        'search: for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    for j in grid.get(&[x + dx, y + dy, z + dz]).into_iter().flatten() {
+                        if mesh.positions[*j].subv(*p).square_size() <= distance * distance {
+                            found = Some(*j);
+                            break 'search;
+                        }
+                    }
+                }
+            }
+        }
+        remap.push(found.unwrap_or(i));
+        if found.is_none() {
+            grid.entry([x, y, z]).or_default().push(i);
+        }
+    }
+
+    for triangle in &mut mesh.triangles {
+        *triangle = triangle.map(|i| remap[i]);
+    }
+    mesh.triangles.retain(|[a, b, c]| a != b && b != c && c != a);
+    compact(mesh);
+}
+
+// Alternates a shrinking Laplacian step (lambda > 0) with an inflating one
+// (mu < -lambda), smoothing the surface without losing its volume
+pub fn taubin(mesh: &mut Mesh, iterations: usize, lambda: f64, mu: f64) {
+    let neighbors = neighbors(mesh);
+    for _ in 0..iterations {
+        for factor in [lambda, mu] {
+            mesh.positions = mesh
+                .positions
+                .iter()
+                .zip(&neighbors)
+                .map(|(p, neighbors)| {
+                    if neighbors.is_empty() {
+                        return *p;
+                    }
+                    let mut center = Vector::new(0.0, 0.0, 0.0);
+                    for j in neighbors {
+                        center = center.addv(mesh.positions[*j]);
+                    }
+                    let center = center.divf(neighbors.len() as f64);
+                    p.addv(center.subv(*p).mulf(factor))
+                })
+                .collect();
+        }
+    }
+}
+
+fn neighbors(mesh: &Mesh) -> Vec<Vec<usize>> {
+    let mut neighbors = vec![Vec::new(); mesh.positions.len()];
+    for [a, b, c] in &mesh.triangles {
+        for (i, j) in [(a, b), (b, c), (c, a)] {
+            neighbors[*i].push(*j);
+            neighbors[*j].push(*i);
+        }
+    }
+    for list in &mut neighbors {
+        list.sort_unstable();
+        list.dedup();
+    }
+    neighbors
+}
+
+// Drops the vertices no triangle uses
+fn compact(mesh: &mut Mesh) {
+    let mut remap = vec![usize::MAX; mesh.positions.len()];
+    let mut kept = 0;
+    for i in mesh.triangles.iter().flatten() {
+        if remap[*i] == usize::MAX {
+            remap[*i] = kept;
+            kept += 1;
+        }
+    }
+    let mut order = vec![0; kept];
+    for (i, target) in remap.iter().enumerate() {
+        if *target != usize::MAX {
+            order[*target] = i;
+        }
+    }
+    mesh.positions = order.iter().map(|i| mesh.positions[*i]).collect();
+    for vectors in [&mut mesh.velocities, &mut mesh.vorticities].into_iter().flatten() {
+        *vectors = order.iter().map(|i| vectors[*i]).collect();
+    }
+    for scalars in [&mut mesh.densities, &mut mesh.curvatures].into_iter().flatten() {
+        *scalars = order.iter().map(|i| scalars[*i]).collect();
+    }
+    for triangle in &mut mesh.triangles {
+        *triangle = triangle.map(|i| remap[i]);
+    }
+}
+
+// Symmetric 4x4 error quadric of Garland and Heckbert, upper triangle
+#[derive(Clone, Copy, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    // Squared distance to the plane n·x + d = 0, times `weight`
+    fn plane(n: Vector, d: f64, weight: f64) -> Self {
+        let [a, b, c] = [n.get_x(), n.get_y(), n.get_z()];
+        Self([a * a, a * b, a * c, a * d, b * b, b * c, b * d, c * c, c * d, d * d].map(|q| q * weight))
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        Self(std::array::from_fn(|k| self.0[k] + other.0[k]))
+    }
+
+    // vᵀQv with v = (x, y, z, 1)
+    fn error(&self, p: Vector) -> f64 {
+        let v = [p.get_x(), p.get_y(), p.get_z(), 1.0];
+        let mut k = 0;
+        let mut error = 0.0;
+        for i in 0..4 {
+            for j in i..4 {
+                let twice = if i == j { 1.0 } else { 2.0 };
+                error += twice * self.0[k] * v[i] * v[j];
+                k += 1;
+            }
+        }
+        error
+    }
+
+    // Point of least error, None when the quadric is singular (flat regions)
+    fn minimum(&self) -> Option<Vector> {
+        let q = &self.0;
+        let a = Matrix::new([[q[0], q[1], q[2]], [q[1], q[4], q[5]], [q[2], q[5], q[7]]]);
+        Some(a.inverse()?.mulv(Vector::new(-q[3], -q[6], -q[8])))
+    }
+}
+
+// Edge collapse waiting in the queue, outdated once either end has moved
+struct Collapse {
+    cost: f64,
+    edge: (usize, usize),
+    versions: (u32, u32),
+    target: Vector,
+}
+
+impl PartialEq for Collapse {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Collapse {}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Reversed, the cheapest collapse is on top of the heap
+impl Ord for Collapse {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.total_cmp(&self.cost).then_with(|| other.edge.cmp(&self.edge))
+    }
+}
+
+// Collapses the cheapest edges until at most `target` triangles are left.
+// Collapses that would fold a triangle over or pinch the surface into a non
+// manifold are skipped, open borders are held in place by extra planes.
+pub fn decimate(mesh: &mut Mesh, target: usize) {
+    let mut positions = mesh.positions.clone();
+    let mut triangles = mesh.triangles.clone();
+    let mut alive = vec![true; triangles.len()];
+    let mut faces: Vec<Vec<usize>> = vec![Vec::new(); positions.len()];
+    for (f, triangle) in triangles.iter().enumerate() {
+        for i in triangle {
+            faces[*i].push(f);
+        }
+    }
+
+    let mut quadrics = vec![Quadric::default(); positions.len()];
+    let mut edge_faces: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+    for (f, [a, b, c]) in triangles.iter().enumerate() {
+        for (i, j) in [(a, b), (b, c), (c, a)] {
+            edge_faces.entry((*i.min(j), *i.max(j))).or_default().push(f);
+        }
+        let normal = positions[*b].subv(positions[*a]).cross(positions[*c].subv(positions[*a]));
+        let area = normal.square_size().sqrt();
+        if area == 0.0 {
+            continue;
+        }
+        let n = normal.divf(area);
+        let plane = Quadric::plane(n, -n.dot(positions[*a]), area / 2.0);
+        for i in [a, b, c] {
+            quadrics[*i] = quadrics[*i].add(&plane);
+        }
+    }
+    // Plane through every border edge, perpendicular to its triangle
+    for ((a, b), list) in &edge_faces {
+        if let [f] = list[..] {
+            let [x, y, z] = triangles[f];
+            let normal = positions[y].subv(positions[x]).cross(positions[z].subv(positions[x]));
+            let edge = positions[*b].subv(positions[*a]);
+            let side = edge.cross(normal);
+            let size = side.square_size().sqrt();
+            if size > 0.0 {
+                let n = side.divf(size);
+                let border = Quadric::plane(n, -n.dot(positions[*a]), edge.square_size() * 1000.0);
+                quadrics[*a] = quadrics[*a].add(&border);
+                quadrics[*b] = quadrics[*b].add(&border);
+            }
+        }
+    }
+
+    let mut versions = vec![0u32; positions.len()];
+    let candidate = |a: usize, b: usize, positions: &[Vector], quadrics: &[Quadric], versions: &[u32]| {
+        let quadric = quadrics[a].add(&quadrics[b]);
+        let (p_a, p_b) = (positions[a], positions[b]);
+        let middle = p_a.addv(p_b).divf(2.0);
+        let length = p_b.subv(p_a).square_size();
+        let mut options = vec![p_a, p_b, middle];
+        // Far away optima come from nearly singular quadrics
+        if let Some(best) = quadric.minimum().filter(|p| p.subv(middle).square_size() <= length) {
+            options.push(best);
+        }
+        let (cost, target) = options
+            .into_iter()
+            .map(|p| (quadric.error(p), p))
+            .min_by(|x, y| x.0.total_cmp(&y.0))
+            .unwrap();
+        Collapse {
+            cost,
+            edge: (a, b),
+            versions: (versions[a], versions[b]),
+            target,
+        }
+    };
+
+    let mut heap: BinaryHeap<Collapse> = edge_faces
+        .keys()
+        .map(|(a, b)| candidate(*a, *b, &positions, &quadrics, &versions))
+        .collect();
+    let mut count = alive.iter().filter(|a| **a).count();
+
+    while count > target {
+        let Some(collapse) = heap.pop() else {
+            break;
+        };
+        let (a, b) = collapse.edge;
+        if (versions[a], versions[b]) != collapse.versions {
+            continue;
+        }
+
+        // Link condition: the ends may only share the tips of the triangles
+        // around the edge
+        let shared: Vec<usize> = faces[a].iter().filter(|f| triangles[**f].contains(&b)).copied().collect();
+        if ring(&faces, &triangles, a).intersection(&ring(&faces, &triangles, b)).count() != shared.len() {
+            continue;
+        }
+
+        // No triangle may flip or degenerate when its corner moves
+        let folds = |v: usize| {
+            faces[v].iter().filter(|f| !shared.contains(f)).any(|f| {
+                let corners = triangles[*f].map(|i| positions[i]);
+                let moved = triangles[*f].map(|i| if i == v { collapse.target } else { positions[i] });
+                let normal = |[p, q, r]: [Vector; 3]| q.subv(p).cross(r.subv(p));
+                let (before, after) = (normal(corners), normal(moved));
+                after.dot(before) <= 0.0 || after.square_size() < 1e-6 * before.square_size()
+            })
+        };
+        if folds(a) || folds(b) {
+            continue;
+        }
+
+        for f in &shared {
+            alive[*f] = false;
+        }
+        count -= shared.len();
+        let moved = std::mem::take(&mut faces[b]);
+        for f in moved {
+            if !alive[f] {
+                continue;
+            }
+            for i in &mut triangles[f] {
+                if *i == b {
+                    *i = a;
+                }
+            }
+            faces[a].push(f);
+        }
+        faces[a].retain(|f| alive[*f]);
+        positions[a] = collapse.target;
+        quadrics[a] = quadrics[a].add(&quadrics[b]);
+        versions[a] += 1;
+        versions[b] += 1;
+
+        for i in ring(&faces, &triangles, a) {
+            heap.push(candidate(a.min(i), a.max(i), &positions, &quadrics, &versions));
+        }
+    }
+
+    mesh.positions = positions;
+    mesh.triangles = triangles.into_iter().zip(alive).filter(|(_, alive)| *alive).map(|(t, _)| t).collect();
+    compact(mesh);
+}
+
+// Vertices sharing a triangle with v
+fn ring(faces: &[Vec<usize>], triangles: &[[usize; 3]], v: usize) -> HashSet<usize> {
+    faces[v].iter().flat_map(|f| triangles[*f]).filter(|i| *i != v).collect()
+}
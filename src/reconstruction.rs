@@ -0,0 +1,892 @@
+// Surface reconstruction: the scalar field polygonized by the Renderer and its
+// settings. Lengths are in simulation units, `padding` and the kernel radii in
+// smoothing lengths.
+//
+// Set from a preset, then the scene file, then the command line:
+//     "reconstruction": { "preset": "preview", "method": "zhu_bridson", "mesher": "surface_nets", "voxel_size": 0.3 }
+
+use std::{collections::HashMap, f64::consts::PI, ops::*, sync::Arc};
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{eigen_value, real::Real, vectors::Vector, voxel, DensityPosition};
+
+static POLY6C: f64 = 315.0_f64 / 64.0_f64 / PI;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Method {
+    Anisotropic,
+    Isotropic,
+    ZhuBridson,
+    Solenthaler,
+}
+
+impl Method {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "anisotropic" => Some(Self::Anisotropic),
+            "isotropic" => Some(Self::Isotropic),
+            "zhu-bridson" | "zhu_bridson" => Some(Self::ZhuBridson),
+            "solenthaler" => Some(Self::Solenthaler),
+            _ => None,
+        }
+    }
+}
+
+// Polygonizer run over the field
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mesher {
+    MarchingCubes,
+    SurfaceNets,
+}
+
+impl Mesher {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "marching-cubes" | "marching_cubes" => Some(Self::MarchingCubes),
+            "surface-nets" | "surface_nets" => Some(Self::SurfaceNets),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReconstructionParams {
+    pub method: Method,
+    pub mesher: Mesher,
+    // Field value of the surface with anisotropic kernels
+    pub iso_level: f64,
+    // Volume fraction of the surface with isotropic kernels
+    pub volume_fraction: f64,
+    // Edge of a field voxel
+    pub voxel_size: f64,
+    // Scales the anisotropic kernels, smaller gives thinner sheets and drops
+    pub kernel_scale: f64,
+    // Below this many neighbors a particle gets a round kernel
+    pub min_neighbors: usize,
+    // Radius of that round kernel
+    pub isolated_radius: f64,
+    // Largest ratio between the longest and the shortest kernel axis
+    pub max_anisotropy: f64,
+    // Margin around the particles' bounding box
+    pub padding: f64,
+    // Isotropic and Zhu-Bridson kernel radius, and the Zhu-Bridson particle radius
+    pub kernel_radius: f64,
+    pub particle_radius: f64,
+    // Solenthaler: the radius shrinks between these largest eigenvalues of
+    // the Jacobian of the averaged position
+    pub t_low: f64,
+    pub t_high: f64,
+    // Post-processing, see postprocess.rs. Vertices closer than this many
+    // voxels are merged, 0 keeps them apart
+    pub weld_distance: f64,
+    // Taubin smoothing passes and their shrinking and inflating factors
+    pub smooth_iterations: usize,
+    pub smooth_lambda: f64,
+    pub smooth_mu: f64,
+    // Decimates down to this many triangles, 0 keeps them all
+    pub target_triangles: usize,
+    // Blends the field of this many frames on each side, weighted by
+    // `temporal_falloff` to the power of their distance, 0 turns it off
+    pub temporal_frames: usize,
+    pub temporal_falloff: f64,
+    // Clips the surface against the simulation box and the scene colliders
+    pub clip: bool,
+}
+
+impl ReconstructionParams {
+    pub fn production() -> Self {
+        Self {
+            method: Method::Anisotropic,
+            mesher: Mesher::MarchingCubes,
+            iso_level: 0.08,
+            volume_fraction: 0.5,
+            voxel_size: 0.23,
+            kernel_scale: 1.0 / 2.5,
+            min_neighbors: 35,
+            isolated_radius: 0.6,
+            max_anisotropy: 5.0,
+            padding: 4.0,
+            kernel_radius: 1.0,
+            particle_radius: 0.3,
+            t_low: 0.4,
+            t_high: 3.5,
+            weld_distance: 0.0,
+            smooth_iterations: 0,
+            smooth_lambda: 0.5,
+            smooth_mu: -0.53,
+            target_triangles: 0,
+            temporal_frames: 0,
+            temporal_falloff: 0.5,
+            clip: true,
+        }
+    }
+
+    // Twice coarser voxels, 8 times fewer field samples
+    pub fn preview() -> Self {
+        Self {
+            voxel_size: 0.46,
+            ..Self::production()
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "production" => Some(Self::production()),
+            "preview" => Some(Self::preview()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for ReconstructionParams {
+    fn default() -> Self {
+        Self::production()
+    }
+}
+
+// Settings given in the scene file or on the command line, applied over a preset
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReconstructionConfig {
+    pub preset: Option<String>,
+    pub method: Option<Method>,
+    pub mesher: Option<Mesher>,
+    pub iso_level: Option<f64>,
+    pub volume_fraction: Option<f64>,
+    pub voxel_size: Option<f64>,
+    pub kernel_scale: Option<f64>,
+    pub min_neighbors: Option<usize>,
+    pub isolated_radius: Option<f64>,
+    pub max_anisotropy: Option<f64>,
+    pub padding: Option<f64>,
+    pub kernel_radius: Option<f64>,
+    pub particle_radius: Option<f64>,
+    pub t_low: Option<f64>,
+    pub t_high: Option<f64>,
+    pub weld_distance: Option<f64>,
+    pub smooth_iterations: Option<usize>,
+    pub smooth_lambda: Option<f64>,
+    pub smooth_mu: Option<f64>,
+    pub target_triangles: Option<usize>,
+    pub temporal_frames: Option<usize>,
+    pub temporal_falloff: Option<f64>,
+    pub clip: Option<bool>,
+}
+
+impl ReconstructionConfig {
+    // Settings of `other` win
+    pub fn overridden_by(self, other: Self) -> Self {
+        Self {
+            preset: other.preset.or(self.preset),
+            method: other.method.or(self.method),
+            mesher: other.mesher.or(self.mesher),
+            iso_level: other.iso_level.or(self.iso_level),
+            volume_fraction: other.volume_fraction.or(self.volume_fraction),
+            voxel_size: other.voxel_size.or(self.voxel_size),
+            kernel_scale: other.kernel_scale.or(self.kernel_scale),
+            min_neighbors: other.min_neighbors.or(self.min_neighbors),
+            isolated_radius: other.isolated_radius.or(self.isolated_radius),
+            max_anisotropy: other.max_anisotropy.or(self.max_anisotropy),
+            padding: other.padding.or(self.padding),
+            kernel_radius: other.kernel_radius.or(self.kernel_radius),
+            particle_radius: other.particle_radius.or(self.particle_radius),
+            t_low: other.t_low.or(self.t_low),
+            t_high: other.t_high.or(self.t_high),
+            weld_distance: other.weld_distance.or(self.weld_distance),
+            smooth_iterations: other.smooth_iterations.or(self.smooth_iterations),
+            smooth_lambda: other.smooth_lambda.or(self.smooth_lambda),
+            smooth_mu: other.smooth_mu.or(self.smooth_mu),
+            target_triangles: other.target_triangles.or(self.target_triangles),
+            temporal_frames: other.temporal_frames.or(self.temporal_frames),
+            temporal_falloff: other.temporal_falloff.or(self.temporal_falloff),
+            clip: other.clip.or(self.clip),
+        }
+    }
+
+    // `domain` is the size of the box the particles stay in, `h` the
+    // smoothing length, both in simulation units
+    pub fn params(&self, domain: Vector, h: f64) -> Result<ReconstructionParams, String> {
+        let mut params = match &self.preset {
+            Some(name) => ReconstructionParams::preset(name)
+                .ok_or_else(|| format!("unknown reconstruction preset {}, expected preview or production", name))?,
+            None => ReconstructionParams::default(),
+        };
+        params.method = self.method.unwrap_or(params.method);
+        params.mesher = self.mesher.unwrap_or(params.mesher);
+        params.iso_level = self.iso_level.unwrap_or(params.iso_level);
+        params.volume_fraction = self.volume_fraction.unwrap_or(params.volume_fraction);
+        params.voxel_size = self.voxel_size.unwrap_or(params.voxel_size);
+        params.kernel_scale = self.kernel_scale.unwrap_or(params.kernel_scale);
+        params.min_neighbors = self.min_neighbors.unwrap_or(params.min_neighbors);
+        params.isolated_radius = self.isolated_radius.unwrap_or(params.isolated_radius);
+        params.max_anisotropy = self.max_anisotropy.unwrap_or(params.max_anisotropy);
+        params.padding = self.padding.unwrap_or(params.padding);
+        params.kernel_radius = self.kernel_radius.unwrap_or(params.kernel_radius);
+        params.particle_radius = self.particle_radius.unwrap_or(params.particle_radius);
+        params.t_low = self.t_low.unwrap_or(params.t_low);
+        params.t_high = self.t_high.unwrap_or(params.t_high);
+        params.weld_distance = self.weld_distance.unwrap_or(params.weld_distance);
+        params.smooth_iterations = self.smooth_iterations.unwrap_or(params.smooth_iterations);
+        params.smooth_lambda = self.smooth_lambda.unwrap_or(params.smooth_lambda);
+        params.smooth_mu = self.smooth_mu.unwrap_or(params.smooth_mu);
+        params.target_triangles = self.target_triangles.unwrap_or(params.target_triangles);
+        params.temporal_frames = self.temporal_frames.unwrap_or(params.temporal_frames);
+        params.temporal_falloff = self.temporal_falloff.unwrap_or(params.temporal_falloff);
+        params.clip = self.clip.unwrap_or(params.clip);
+
+        // NaN fails every comparison, so the checks are written to accept
+        let positive = |v: f64| v.is_finite() && v > 0.0;
+        if !positive(params.iso_level) || !positive(params.volume_fraction) {
+            return Err("iso_level and volume_fraction must be positive".to_string());
+        }
+        if !positive(params.voxel_size) || !positive(params.kernel_scale) || !positive(params.isolated_radius) {
+            return Err("voxel_size, kernel_scale and isolated_radius must be positive".to_string());
+        }
+        if !positive(params.kernel_radius) || !positive(params.particle_radius) {
+            return Err("kernel_radius and particle_radius must be positive".to_string());
+        }
+        if !(params.padding.is_finite() && params.padding >= 0.0) {
+            return Err("padding can't be negative".to_string());
+        }
+        if !(params.max_anisotropy.is_finite() && params.max_anisotropy >= 1.0) {
+            return Err("max_anisotropy must be at least 1".to_string());
+        }
+        // Lattice coordinates are packed into voxel::MASK per axis
+        let side = (0..3).map(|k| domain.get(k)).fold(0.0, f64::max) + 2.0 * params.padding * h;
+        if side / params.voxel_size > voxel::MASK as f64 {
+            return Err(format!(
+                "voxel_size {} is too small for a {} wide domain, the lattice is limited to {} points per axis",
+                params.voxel_size,
+                side,
+                voxel::MASK
+            ));
+        }
+        if !(params.t_low.is_finite() && params.t_high > params.t_low && params.t_high.is_finite()) {
+            return Err("t_high must be above t_low".to_string());
+        }
+        if !(params.weld_distance.is_finite() && params.weld_distance >= 0.0) {
+            return Err("weld_distance can't be negative".to_string());
+        }
+        // Otherwise the inflating step doesn't make up for the shrinking one
+        if !(positive(params.smooth_lambda) && params.smooth_mu.is_finite() && params.smooth_mu < -params.smooth_lambda) {
+            return Err("smoothing needs smooth_lambda > 0 and smooth_mu < -smooth_lambda".to_string());
+        }
+        if !(params.temporal_falloff > 0.0 && params.temporal_falloff <= 1.0) {
+            return Err("temporal_falloff must be in (0, 1]".to_string());
+        }
+        Ok(params)
+    }
+}
+
+// Field sampled on the voxel lattice. Every lattice point accumulates
+// `channels` values from the particles reaching it, `field` then turns them
+// into a value that is above `iso_level` inside the fluid.
+pub trait Reconstruction<T: Real>: Send + Sync {
+    fn channels(&self) -> usize;
+
+    // Per frame neighbourhoods and kernels, `bounds` holds every particle
+    fn prepare(&mut self, particles: &[DensityPosition<T>], h: T, mass: T, bounds: (Vector<T>, Vector<T>));
+
+    // True when `blend` takes care of the frames around this one. Otherwise
+    // the Renderer averages the channels splatted by each of them.
+    fn blends_kernels(&self) -> bool {
+        false
+    }
+
+    // After `prepare`, blends in the kernels of the same particles (by id) in
+    // the frames around this one, given with their weight
+    fn blend(
+        &mut self,
+        _particles: &[DensityPosition<T>],
+        _neighbors: &[(f64, Arc<Vec<DensityPosition<T>>>)],
+        _h: T,
+        _mass: T,
+        _bounds: (Vector<T>, Vector<T>),
+    ) {
+    }
+
+    // Box reached by the kernel of particle i
+    fn support(&self, i: usize) -> [Vector<T>; 2];
+
+    // Adds the contribution of particle i at x, false when it has none
+    fn splat(&self, i: usize, x: Vector<T>, values: &mut [T]) -> bool;
+
+    // Weight of particle i at x under the kernel `splat` adds, zero outside
+    // of it. Attributes are interpolated onto the mesh with it.
+    fn kernel(&self, i: usize, x: Vector<T>) -> T;
+
+    fn field(&self, x: Vector<T>, values: &[T]) -> T;
+
+    fn iso_level(&self) -> T;
+}
+
+pub fn build<T: Real>(params: ReconstructionParams) -> Box<dyn Reconstruction<T>> {
+    match params.method {
+        Method::Anisotropic => Box::new(Anisotropic::new(params)),
+        Method::Isotropic => Box::new(Isotropic::new(params)),
+        Method::ZhuBridson => Box::new(ZhuBridson::new(params, false)),
+        Method::Solenthaler => Box::new(ZhuBridson::new(params, true)),
+    }
+}
+
+// Neighbour weight of the anisotropic kernels
+pub fn weight<T: Real>(r: T, h: T) -> T {
+    let two = T::of(2.0);
+    if r >= two * h {
+        return T::zero();
+    }
+    let d = r / (two * h);
+    T::one() - d * d * d
+}
+
+fn poly6<T: Real>(u2: T) -> T {
+    if u2 > T::one() {
+        return T::zero();
+    }
+    let d = T::one() - u2;
+    T::of(POLY6C).mul(d.powi(3))
+}
+
+// Yu & Turk 2013: smoothed particle centers and kernels stretched along the
+// principal axes of the neighbourhood
+pub struct Anisotropic<T: Real> {
+    params: ReconstructionParams,
+    factor: Vec<T>,
+    det_g: Vec<T>,
+    g: Vec<[[T; 3]; 3]>,
+    // Covariance of the kernel ellipsoid, blended across frames
+    shapes: Vec<[[f64; 3]; 3]>,
+    bbox: Vec<[Vector<T>; 2]>,
+    new_pos: Vec<Vector<T>>,
+    preprocess_grid: Vec<Vec<Vec<Vec<usize>>>>,
+}
+
+impl<T: Real> Anisotropic<T> {
+    pub fn new(params: ReconstructionParams) -> Self {
+        Self {
+            params,
+            factor: Vec::new(),
+            det_g: Vec::new(),
+            g: Vec::new(),
+            shapes: Vec::new(),
+            bbox: Vec::new(),
+            new_pos: Vec::new(),
+            preprocess_grid: vec![vec![vec![Vec::<usize>::new(); 120]; 120]; 120],
+        }
+    }
+
+    // Unscaled kernel of particle i at x, poly6 of |G (x - x̄_i)|²
+    fn shape(&self, i: usize, x: Vector<T>) -> T {
+        let g = &self.g[i];
+        let r = x.subv(self.new_pos[i]);
+        let gr = Vector::new(
+            g[0][0] * r.get_x() + g[0][1] * r.get_y() + g[0][2] * r.get_z(),
+            g[1][0] * r.get_x() + g[1][1] * r.get_y() + g[1][2] * r.get_z(),
+            g[2][0] * r.get_x() + g[2][1] * r.get_y() + g[2][2] * r.get_z(),
+        );
+        poly6(gr.square_size())
+    }
+}
+
+impl<T: Real> Reconstruction<T> for Anisotropic<T> {
+    fn channels(&self) -> usize {
+        1
+    }
+
+    fn prepare(&mut self, particles: &[DensityPosition<T>], h: T, mass: T, (min_vector, max_vector): (Vector<T>, Vector<T>)) {
+        let n = particles.len();
+        self.det_g = vec![T::zero(); n];
+        self.g = vec![[[T::zero(); 3]; 3]; n];
+        self.shapes = vec![[[0.0; 3]; 3]; n];
+        self.bbox = vec![[Vector::zero(); 2]; n];
+        self.new_pos = vec![Vector::zero(); n];
+        let diff_vector = max_vector.subv(min_vector);
+
+        let preprocess_grid = &mut self.preprocess_grid; //vec![vec![vec![Vec::<usize>::new(); 120];120];120];
+
+        let (hundred, two) = (T::of(100.0), T::of(2.0));
+        let length = Vector::new(
+            diff_vector.get_x().div(hundred).max(two * h),
+            diff_vector.get_y().div(hundred).max(two * h),
+            diff_vector.get_z().div(hundred).max(two * h),
+        );
+
+        
+     
+        for (i, particle) in particles.iter().enumerate() {
+            let input = particle.vector;
+            let grid_x = input
+                .get_x()
+                .sub(min_vector.get_x())
+                .div(length.get_x())
+                .ceil().as_f64() as usize;
+            let grid_y = input
+                .get_y()
+                .sub(min_vector.get_y())
+                .div(length.get_y())
+                .ceil().as_f64() as usize;
+            let grid_z = input
+                .get_z()
+                .sub(min_vector.get_z())
+                .div(length.get_z())
+                .ceil().as_f64() as usize;
+            preprocess_grid[grid_x][grid_y][grid_z].push(i);
+            
+        }
+
+        ((0..particles.len()), &mut self.det_g, &mut self.bbox, &mut self.new_pos, &mut self.g, &mut self.shapes).into_par_iter().for_each(|(i, det_g, bbox, new_pos_g, g, shape) | {
+            let mut neighbor = 0;
+            let mut sum_wij = T::zero();
+            let mut cov = [[T::zero(); 3]; 3];
+
+            let mut new_pos = Vector::zero();
+            let input = particles[i].vector;
+            let grid_x = input
+                .get_x()
+                .sub(min_vector.get_x())
+                .div(length.get_x())
+                .ceil().as_f64() as i32;
+            let grid_y = input
+                .get_y()
+                .sub(min_vector.get_y())
+                .div(length.get_y())
+                .ceil().as_f64() as i32;
+            let grid_z = input
+                .get_z()
+                .sub(min_vector.get_z())
+                .div(length.get_z())
+                .ceil().as_f64() as i32;
+
+            for x in 0..3_i32 {
+                if x.sub(1).add(grid_x) < 0 {
+                    continue;
+                }
+                for y in 0..3_i32 {
+                    if y.sub(1).add(grid_y) < 0 {
+                        continue;
+                    }
+                    for z in 0..3_i32 {
+                        if z.sub(1).add(grid_z) < 0 {
+                            continue;
+                        }
+
+                        for j in &preprocess_grid[grid_x.sub(1).add(x) as usize]
+                            [grid_y.sub(1).add(y) as usize]
+                            [grid_z.sub(1).add(z) as usize]
+                        {
+                            let j_vector = particles[*j].vector;
+                            let r = input.subv(j_vector).square_size().sqrt();
+                            let wij = weight(r, h);
+                            if wij == T::zero() {
+                                continue;
+                            }
+                            
+                            neighbor += 1;
+                            sum_wij += wij;
+
+                            new_pos = new_pos.addv(j_vector.mulf(wij));
+                        }
+                    }
+                }
+            }
+
+            new_pos = new_pos.divf(sum_wij);
+
+            for x in 0..3_i32 {
+                if x.sub(1).add(grid_x) < 0 {
+                    continue;
+                }
+                for y in 0..3_i32 {
+                    if y.sub(1).add(grid_y) < 0 {
+                        continue;
+                    }
+                    for z in 0..3_i32 {
+                        if z.sub(1).add(grid_z) < 0 {
+                            continue;
+                        }
+
+                        for j in &preprocess_grid[grid_x.sub(1).add(x) as usize]
+                            [grid_y.sub(1).add(y) as usize]
+                            [grid_z.sub(1).add(z) as usize]
+                        {
+                            let j_vector = particles[*j].vector;
+                            let r = input.subv(j_vector).square_size().sqrt();
+                            let wij = weight(r, h);
+                            if wij == T::zero() {
+                                continue;
+                            }
+
+                            let delta = j_vector.subv(new_pos);
+                            for l in 0..3 {
+                                for m in 0..3 {
+                                    cov[l as usize][m as usize] +=
+                                        wij * delta.get(l) * delta.get(m);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            for l in 0..3 {
+                for m in 0..3 {
+                    cov[l as usize][m as usize] /= sum_wij;
+                }
+            }
+
+            let (eiv,  mut eig) = eigen_value::eigen(cov.map(|row| row.map(|v| v.as_f64())));
+
+            let params = &self.params;
+            eig[0] = eig[0].max(eig[2] / params.max_anisotropy);
+            eig[1] = eig[1].max(eig[2] / params.max_anisotropy);
+
+            if neighbor < params.min_neighbors {
+                eig[0] = params.isolated_radius;
+                eig[1] = params.isolated_radius;
+                eig[2] = params.isolated_radius;
+            } else {
+                for j in 0..3 {
+                    eig[j] *= params.kernel_scale;
+                }
+            }
+
+            let h = h.as_f64();
+            let mut m = [[0.0_f64; 3]; 3];
+            let mut det = 1.0;
+            for l in 0..3 {
+                for n in 0..3 {
+                    m[l][n] = eiv[l][n] * eig[n] * h;
+                    g[l][n] = T::of(eiv[n][l] / eig[l] / h);
+                }
+                det *= eig[l] / h;
+            }
+            *det_g = T::of(det);
+            *shape = std::array::from_fn(|l| std::array::from_fn(|n| (0..3).map(|k| m[l][k] * m[n][k]).sum()));
+
+            let halfbox = Vector::new(
+                Vector::new(m[0][0], m[0][1], m[0][2]).square_size().sqrt(),
+                Vector::new(m[1][0], m[1][1], m[1][2]).square_size().sqrt(),
+                Vector::new(m[2][0], m[2][1], m[2][2]).square_size().sqrt(),
+            )
+            .cast();
+
+            bbox[0] = new_pos.subv(halfbox);
+            bbox[1] = new_pos.addv(halfbox);
+            *new_pos_g = new_pos.clone();
+        });
+        
+
+        for particle in particles {
+            let grid_x = particle
+                .vector
+                .get_x()
+                .sub(min_vector.get_x())
+                .div(length.get_x())
+                .ceil().as_f64() as usize;
+            let grid_y = particle
+                .vector
+                .get_y()
+                .sub(min_vector.get_y())
+                .div(length.get_y())
+                .ceil().as_f64() as usize;
+            let grid_z = particle
+                .vector
+                .get_z()
+                .sub(min_vector.get_z())
+                .div(length.get_z())
+                .ceil().as_f64() as usize;
+            self.preprocess_grid[grid_x][grid_y][grid_z].pop();
+        }
+
+        self.factor = particles
+            .iter()
+            .zip(&self.det_g)
+            .map(|(p, det_g)| mass / p.density * *det_g)
+            .collect();
+    }
+
+    fn blends_kernels(&self) -> bool {
+        true
+    }
+
+    // Averages the kernel covariances, then rebuilds symmetric kernels from
+    // them. The eigenvectors can't be averaged, their signs and order change
+    // from a frame to the next.
+    fn blend(
+        &mut self,
+        particles: &[DensityPosition<T>],
+        neighbors: &[(f64, Arc<Vec<DensityPosition<T>>>)],
+        h: T,
+        mass: T,
+        bounds: (Vector<T>, Vector<T>),
+    ) {
+        if neighbors.is_empty() {
+            return;
+        }
+        let index: HashMap<u64, usize> = particles.iter().enumerate().map(|(i, p)| (p.id, i)).collect();
+        let own = (
+            std::mem::take(&mut self.shapes),
+            std::mem::take(&mut self.new_pos),
+        );
+        let mut shapes = own.0.clone();
+        let mut weights = vec![1.0; particles.len()];
+        for (weight, frame) in neighbors {
+            self.prepare(frame, h, mass, bounds);
+            for (j, particle) in frame.iter().enumerate() {
+                if let Some(i) = index.get(&particle.id) {
+                    for (row, other) in shapes[*i].iter_mut().zip(&self.shapes[j]) {
+                        for (value, other) in row.iter_mut().zip(other) {
+                            *value += weight * other;
+                        }
+                    }
+                    weights[*i] += weight;
+                }
+            }
+        }
+        (self.shapes, self.new_pos) = own;
+
+        let h = h.as_f64();
+        let kernels: Vec<_> = shapes
+            .par_iter()
+            .zip(&weights)
+            .map(|(shape, weight)| {
+                let shape = shape.map(|row| row.map(|v| v / weight));
+                let (eiv, eig) = eigen_value::eigen(shape);
+                // Axes of the ellipsoid, as the eigenvalues of prepare
+                let axes = eig.map(|e| e.max(0.0).sqrt() / h);
+                let mut g = [[T::zero(); 3]; 3];
+                for (l, row) in g.iter_mut().enumerate() {
+                    for (n, value) in row.iter_mut().enumerate() {
+                        *value = T::of((0..3).map(|k| eiv[l][k] * eiv[n][k] / axes[k] / h).sum());
+                    }
+                }
+                let det = axes.iter().map(|a| a / h).product::<f64>();
+                let halfbox: Vector = Vector::new(shape[0][0].sqrt(), shape[1][1].sqrt(), shape[2][2].sqrt());
+                (g, T::of(det), halfbox.cast::<T>())
+            })
+            .collect();
+
+        // The neighbouring frames left their own kernels behind
+        self.g = kernels.iter().map(|(g, _, _)| *g).collect();
+        self.det_g = kernels.iter().map(|(_, det, _)| *det).collect();
+        self.bbox = kernels
+            .iter()
+            .zip(&self.new_pos)
+            .map(|((_, _, halfbox), new_pos)| [new_pos.subv(*halfbox), new_pos.addv(*halfbox)])
+            .collect();
+        self.factor = particles
+            .iter()
+            .zip(&self.det_g)
+            .map(|(p, det_g)| mass / p.density * *det_g)
+            .collect();
+    }
+
+    fn support(&self, i: usize) -> [Vector<T>; 2] {
+        self.bbox[i]
+    }
+
+    fn splat(&self, i: usize, x: Vector<T>, values: &mut [T]) -> bool {
+        let weight = self.shape(i, x);
+        if weight == T::zero() {
+            return false;
+        }
+        values[0] += self.factor[i] * weight;
+        true
+    }
+
+    fn kernel(&self, i: usize, x: Vector<T>) -> T {
+        self.factor[i] * self.shape(i, x)
+    }
+
+    fn field(&self, _x: Vector<T>, values: &[T]) -> T {
+        values[0]
+    }
+
+    fn iso_level(&self) -> T {
+        T::of(self.params.iso_level)
+    }
+}
+
+// Plain SPH volume fraction, sum of m/ρ W(x - x_i) with a normalized poly6
+// kernel: 1 inside the fluid, 0 outside
+pub struct Isotropic<T: Real> {
+    params: ReconstructionParams,
+    radius: T,
+    positions: Vec<Vector<T>>,
+    volumes: Vec<T>,
+}
+
+impl<T: Real> Isotropic<T> {
+    pub fn new(params: ReconstructionParams) -> Self {
+        Self {
+            params,
+            radius: T::zero(),
+            positions: Vec::new(),
+            volumes: Vec::new(),
+        }
+    }
+}
+
+impl<T: Real> Reconstruction<T> for Isotropic<T> {
+    fn channels(&self) -> usize {
+        1
+    }
+
+    fn prepare(&mut self, particles: &[DensityPosition<T>], h: T, mass: T, _bounds: (Vector<T>, Vector<T>)) {
+        self.radius = h * T::of(self.params.kernel_radius);
+        self.positions = particles.iter().map(|p| p.vector).collect();
+        self.volumes = particles.iter().map(|p| mass / p.density).collect();
+    }
+
+    fn support(&self, i: usize) -> [Vector<T>; 2] {
+        let r = Vector::new(self.radius, self.radius, self.radius);
+        [self.positions[i].subv(r), self.positions[i].addv(r)]
+    }
+
+    fn splat(&self, i: usize, x: Vector<T>, values: &mut [T]) -> bool {
+        let u2 = x.subv(self.positions[i]).square_size() / (self.radius * self.radius);
+        let weight = poly6(u2);
+        if weight == T::zero() {
+            return false;
+        }
+        values[0] += self.volumes[i] * weight / self.radius.powi(3);
+        true
+    }
+
+    fn kernel(&self, i: usize, x: Vector<T>) -> T {
+        let u2 = x.subv(self.positions[i]).square_size() / (self.radius * self.radius);
+        self.volumes[i] * poly6(u2) / self.radius.powi(3)
+    }
+
+    fn field(&self, _x: Vector<T>, values: &[T]) -> T {
+        values[0]
+    }
+
+    fn iso_level(&self) -> T {
+        T::of(self.params.volume_fraction)
+    }
+}
+
+// Zhu & Bridson 2005: distance to a sphere centered on the kernel weighted
+// average of the particle positions, r - |x - x̄| (positive inside).
+// Solenthaler et al. 2007 shrink the radius where the average moves fast
+// (largest eigenvalue of its Jacobian between t_low and t_high), which removes
+// the bumps in concave regions.
+//
+// Channels: Σw, Σw x_i, and for Solenthaler Σ∇w and Σ x_i ⊗ ∇w
+pub struct ZhuBridson<T: Real> {
+    params: ReconstructionParams,
+    jacobian: bool,
+    radius: T,
+    particle_radius: T,
+    positions: Vec<Vector<T>>,
+}
+
+impl<T: Real> ZhuBridson<T> {
+    pub fn new(params: ReconstructionParams, jacobian: bool) -> Self {
+        Self {
+            params,
+            jacobian,
+            radius: T::zero(),
+            particle_radius: T::zero(),
+            positions: Vec::new(),
+        }
+    }
+
+    // Solenthaler's radius factor from the largest eigenvalue of ∇x̄
+    fn shrink(&self, x_sum: Vector<T>, w_sum: T, values: &[T]) -> f64 {
+        let w = w_sum.as_f64();
+        let grad_w: [f64; 3] = std::array::from_fn(|b| values[4 + b].as_f64());
+        let jacobian: [[f64; 3]; 3] = std::array::from_fn(|a| {
+            std::array::from_fn(|b| values[7 + 3 * a + b].as_f64() / w - x_sum.get(a).as_f64() * grad_w[b] / (w * w))
+        });
+        let symmetric = std::array::from_fn(|a| std::array::from_fn(|b| 0.5 * (jacobian[a][b] + jacobian[b][a])));
+        let (_, eig) = eigen_value::eigen(symmetric);
+        let ev_max = eig.iter().fold(0.0_f64, |max, e| max.max(e.abs()));
+
+        let (t_low, t_high) = (self.params.t_low, self.params.t_high);
+        if ev_max < t_low {
+            return 1.0;
+        }
+        if ev_max > t_high {
+            return 0.0;
+        }
+        let gamma = (t_high - ev_max) / (t_high - t_low);
+        gamma.powi(3) - 3.0 * gamma.powi(2) + 3.0 * gamma
+    }
+}
+
+impl<T: Real> Reconstruction<T> for ZhuBridson<T> {
+    fn channels(&self) -> usize {
+        if self.jacobian { 16 } else { 4 }
+    }
+
+    fn prepare(&mut self, particles: &[DensityPosition<T>], h: T, _mass: T, _bounds: (Vector<T>, Vector<T>)) {
+        self.radius = h * T::of(self.params.kernel_radius);
+        self.particle_radius = h * T::of(self.params.particle_radius);
+        self.positions = particles.iter().map(|p| p.vector).collect();
+    }
+
+    fn support(&self, i: usize) -> [Vector<T>; 2] {
+        let r = Vector::new(self.radius, self.radius, self.radius);
+        [self.positions[i].subv(r), self.positions[i].addv(r)]
+    }
+
+    // w = (1 - s²)³ with s = |x - x_i| / R, ∇w = -6 (1 - s²)² (x - x_i) / R²
+    fn splat(&self, i: usize, x: Vector<T>, values: &mut [T]) -> bool {
+        let d = x.subv(self.positions[i]);
+        let s2 = d.square_size() / (self.radius * self.radius);
+        if s2 >= T::one() {
+            return false;
+        }
+        let k = T::one() - s2;
+        let p = self.positions[i];
+        values[0] += k * k * k;
+        for a in 0..3 {
+            values[1 + a] += k * k * k * p.get(a);
+        }
+        if self.jacobian {
+            let grad = d.mulf(T::of(-6.0) * k * k / (self.radius * self.radius));
+            for b in 0..3 {
+                values[4 + b] += grad.get(b);
+            }
+            for a in 0..3 {
+                for b in 0..3 {
+                    values[7 + 3 * a + b] += p.get(a) * grad.get(b);
+                }
+            }
+        }
+        true
+    }
+
+    fn kernel(&self, i: usize, x: Vector<T>) -> T {
+        let s2 = x.subv(self.positions[i]).square_size() / (self.radius * self.radius);
+        if s2 >= T::one() {
+            return T::zero();
+        }
+        let k = T::one() - s2;
+        k * k * k
+    }
+
+    fn field(&self, x: Vector<T>, values: &[T]) -> T {
+        let w_sum = values[0];
+        if w_sum == T::zero() {
+            return T::zero();
+        }
+        let x_sum = Vector::new(values[1], values[2], values[3]);
+        let average = x_sum.divf(w_sum);
+        let mut radius = self.particle_radius;
+        if self.jacobian {
+            radius *= T::of(self.shrink(x_sum, w_sum, values));
+        }
+        radius - x.subv(average).square_size().sqrt()
+    }
+
+    fn iso_level(&self) -> T {
+        T::zero()
+    }
+}
@@ -0,0 +1,136 @@
+// JSON scene description, loaded with `--scene <path>`:
+// {
+//     "gravity": { "direction": {"x": 0, "y": 0, "z": -1}, "magnitude": 9.82,
+//                  "axis": {"x": 0, "y": 0, "z": 1}, "angular_velocity": 0.5 },
+//     "forces": [
+//         { "type": "wind", "velocity": {"x": 200, "y": 0, "z": 0}, "strength": 2,
+//           "min": {"x": 0, "y": 0, "z": 10}, "start": 1.0, "end": 2.0 },
+//         { "type": "vortex", "center": {"x": 25, "y": 25, "z": 0},
+//           "axis": {"x": 0, "y": 0, "z": 1}, "strength": 500, "radius": 20 }
+//     ],
+//     "reconstruction": { "preset": "preview", "iso_level": 0.1 },
+//     "colliders": [
+//         { "type": "box", "min": {"x": 20, "y": 20, "z": 0}, "max": {"x": 30, "y": 30, "z": 8} },
+//         { "type": "sphere", "center": {"x": 10, "y": 40, "z": 5}, "radius": 4 }
+//     ]
+// }
+
+use std::{error::Error, fs};
+
+use serde::Deserialize;
+
+use crate::{
+    clip::Collider,
+    forces::{Attractor, Drag, ForceField, Gravity, Noise, Scope, Scoped, Vortex, Wind},
+    real::Real,
+    reconstruction::ReconstructionConfig,
+    sph::SPH,
+    vectors::Vector,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Scene {
+    #[serde(default)]
+    pub gravity: Option<GravityConfig>,
+    #[serde(default)]
+    pub forces: Vec<ForceConfig>,
+    // Surface meshing settings, see reconstruction.rs
+    #[serde(default)]
+    pub reconstruction: ReconstructionConfig,
+    // Solids the surface is clipped against, see clip.rs
+    #[serde(default)]
+    pub colliders: Vec<Collider>,
+}
+
+// Magnitude in m/s², converted to simulation units (4 mm) like SPH::g
+#[derive(Debug, Clone, Deserialize)]
+pub struct GravityConfig {
+    pub direction: Vector,
+    pub magnitude: f64,
+    #[serde(default)]
+    pub axis: Option<Vector>,
+    #[serde(default)]
+    pub angular_velocity: f64,
+}
+
+// Every field is in simulation units, `start` and `end` in seconds
+#[derive(Debug, Clone, Deserialize)]
+pub struct ForceConfig {
+    #[serde(flatten)]
+    pub kind: ForceKind,
+    #[serde(default)]
+    pub min: Option<Vector>,
+    #[serde(default)]
+    pub max: Option<Vector>,
+    #[serde(default)]
+    pub start: Option<f64>,
+    #[serde(default)]
+    pub end: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ForceKind {
+    Wind { velocity: Vector, strength: f64 },
+    Attractor { center: Vector, strength: f64, radius: f64 },
+    Repulsor { center: Vector, strength: f64, radius: f64 },
+    Vortex { center: Vector, axis: Vector, strength: f64, radius: f64 },
+    Noise { amplitude: f64, frequency: f64, speed: f64, #[serde(default)] seed: u64 },
+    Drag { coefficient: f64 },
+}
+
+impl Scene {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn apply<T: Real>(&self, sph: &mut SPH<T>) {
+        if let Some(gravity) = &self.gravity {
+            let mut g = Gravity::new(gravity.direction, gravity.magnitude / 0.004);
+            if let Some(axis) = gravity.axis {
+                g = g.rotating(axis, gravity.angular_velocity);
+            }
+            sph.set_gravity(g);
+        }
+
+        for force in &self.forces {
+            sph.add_force(Box::new(force.build()));
+        }
+    }
+}
+
+impl ForceConfig {
+    fn build(&self) -> Scoped {
+        let field: Box<dyn ForceField> = match self.kind {
+            ForceKind::Wind { velocity, strength } => Box::new(Wind { velocity, strength }),
+            ForceKind::Attractor { center, strength, radius } => Box::new(Attractor { center, strength, radius }),
+            ForceKind::Repulsor { center, strength, radius } => Box::new(Attractor {
+                center,
+                strength: -strength,
+                radius,
+            }),
+            ForceKind::Vortex { center, axis, strength, radius } => Box::new(Vortex {
+                center,
+                axis,
+                strength,
+                radius,
+            }),
+            ForceKind::Noise { amplitude, frequency, speed, seed } => {
+                Box::new(Noise::new(amplitude, frequency, speed, seed))
+            }
+            ForceKind::Drag { coefficient } => Box::new(Drag { coefficient }),
+        };
+
+        let everywhere = Scope::everywhere();
+        Scoped {
+            scope: Scope {
+                min: self.min,
+                max: self.max,
+                start: self.start.unwrap_or(everywhere.start),
+                end: self.end.unwrap_or(everywhere.end),
+            },
+            field,
+        }
+    }
+}
@@ -1,51 +1,152 @@
 use rayon::prelude::*;
 use std::{
+    cmp::Ordering,
     f64::consts::PI,
-    ops::{Add, Div, Mul, Sub},
+    ops::{Add, Div, Mul, Range, Sub},
 };
 
-use crate::vectors::Vector;
+use std::collections::HashMap;
 
-pub struct SPH {
-    epsilon: f64,
-    pub mass: f64,
+use serde::{Deserialize, Serialize};
 
-    g: Vector,
-    rest_density: f64,
-    pdist: f64,
-    pub pradi: f64,
+use crate::{
+    elastic::ElasticParams,
+    forces::{ForceField, Gravity},
+    granular::GranularParams,
+    matrix::Matrix,
+    real::Real,
+    vectors::Vector,
+};
+
+const AMBIENT_TEMPERATURE: f64 = 293.15;
+
+// Kernel correction used when densities are re-initialized
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DensityFilter {
+    Shepard,
+    Mls,
+}
+
+// 2D runs in the XY plane (z = 0) with gravity along -Y
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Dimension {
+    Two,
+    Three,
+}
+
+// Constitutive model of a phase, phase 0 is always the default fluid
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Material {
+    Fluid,
+    Granular(GranularParams),
+    Elastic(ElasticParams),
+}
+
+// Rest shape and strain of a particle of an elastic phase
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+struct ElasticState<T: Real> {
+    rest_position: Vector<T>,
+    rest_neighbors: Vec<usize>,
+    rest_correction: Matrix<T>,
+    deformation: Matrix<T>,
+    plastic_strain: Matrix<T>,
+}
+
+// Everything but the external forces is serialized in checkpoints, the forces
+// are rebuilt from the scene file when resuming
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct SPH<T: Real = f64> {
+    dimension: Dimension,
+    epsilon: T,
+    pub mass: T,
+
+    g: Gravity,
+    #[serde(skip)]
+    forces: Vec<Box<dyn ForceField>>,
+    rest_density: T,
+    pdist: T,
+    pub pradi: T,
 
-    pub h: f64,
-    acc_limit: f64,
-    damping: f64,
-    bound_repul: f64,
+    pub h: T,
+    acc_limit: T,
+    damping: T,
+    bound_repul: T,
 
-    kp: f64,
-    visc: f64,
-    tension: f64,
-    dt: f64,
+    kp: T,
+    visc: T,
+    tension: T,
+    dt: T,
 
-    wc: f64,
-    grad_w_2_c: f64,
+    art_visc: T,
+    sound_speed: T,
 
-    pub positions: Vec<Vector>,
-    pub velocities: Vec<Vector>,
-    pub accelerations: Vec<Vector>,
-    pub densities: Vec<f64>,
+    wc: T,
+    grad_w_2_c: T,
+    lap_w_2_c: T,
+
+    // Persistent particle ids, never reused
+    pub ids: Vec<u64>,
+    next_id: u64,
+    // Time each particle was added at
+    births: Vec<T>,
+    // Passive per-particle temperature in kelvin, carried along but not simulated
+    pub temperatures: Vec<T>,
+
+    pub positions: Vec<Vector<T>>,
+    pub velocities: Vec<Vector<T>>,
+    pub accelerations: Vec<Vector<T>>,
+    pub densities: Vec<T>,
+    pub phases: Vec<usize>,
+    // Deviatoric stress of granular particles
+    pub stresses: Vec<Matrix<T>>,
+    artificial_stresses: Vec<Matrix<T>>,
+    materials: Vec<Material>,
+
+    // Only the particles of elastic phases have one, by index
+    elastic_states: HashMap<usize, ElasticState<T>>,
+
+    // Rebuilt from the positions, see restore_grid
+    #[serde(skip)]
     grid: Vec<Vec<Vec<Vec<usize>>>>,
 
-    len: Vector,
-    bounds: Vector,
+    len: Vector<T>,
+    bounds: Vector<T>,
+
+    // Density re-initialization every `reinit_every` steps (0 = disabled)
+    pub reinit_every: usize,
+    pub reinit_filter: DensityFilter,
+
+    // Fickian particle shifting
+    pub shifting: bool,
+    pub shift_coef: T,
+    pub surface_threshold: T,
+
+    step: usize,
 }
 
-impl SPH {
-    pub fn new(bounds: Vector, dt: f64) -> Self {
+impl<T: Real> SPH<T> {
+    pub fn new(bounds: Vector<T>, dt: T) -> Self {
+        Self::with_dimension(bounds, dt, Dimension::Three)
+    }
+
+    pub fn with_dimension(bounds: Vector<T>, dt: T, dimension: Dimension) -> Self {
         let epsilon: f64 = 1e-4;
         let mass: f64 = 0.00020543;
 
-        let g = Vector::new(0.0, 0.0, -9.82_f64.div(0.004));
-        let rest_density: f64 = 600.0_f64.mul(0.004_f64.powi(3));
-        let pdist: f64 = (mass.div(rest_density)).powf(1.0 / 3.0);
+        let down = match dimension {
+            Dimension::Two => Vector::new(0.0, -1.0, 0.0),
+            Dimension::Three => Vector::new(0.0, 0.0, -1.0),
+        };
+        let g = Gravity::new(down, 9.82_f64.div(0.004));
+        let rest_density_3d: f64 = 600.0_f64.mul(0.004_f64.powi(3));
+        let pdist: f64 = (mass.div(rest_density_3d)).powf(1.0 / 3.0);
+        // Same particle spacing in 2D, the density becomes a mass per area
+        let rest_density = match dimension {
+            Dimension::Two => mass.div(pdist.powi(2)),
+            Dimension::Three => rest_density_3d,
+        };
         let pradi: f64 = 0.1;
 
         let h: f64 = 0.01_f64.div(0.004); // kernel radius
@@ -54,72 +155,134 @@ impl SPH {
         let bound_repul: f64 = 10000.0;
 
         let kp: f64 = 3.0_f64.div(0.004_f64.powi(2)); // Pressure Stiffness
-        let visc: f64 = 0.25.mul(0.004); // Viscosity
+        let visc: f64 = 0.25_f64.mul(0.004).mul(rest_density / rest_density_3d); // Viscosity
         let tension: f64 = 150.0; // Surface Tension
+        let art_visc: f64 = 1.0; // Artificial viscosity of granular phases
+        let sound_speed: f64 = kp.sqrt();
 
-        let wc: f64 = (315.0_f64).div(64.0).div(PI).div(h.powi(9));
-        let grad_w_2_c: f64 = 45.0_f64.div(PI).div(h.powi(6));
+        // Poly6, spiky gradient and viscosity laplacian normalization
+        let (wc, grad_w_2_c, lap_w_2_c) = match dimension {
+            Dimension::Two => (
+                4.0_f64.div(PI).div(h.powi(8)),
+                30.0_f64.div(PI).div(h.powi(5)),
+                40.0_f64.div(PI).div(h.powi(5)),
+            ),
+            Dimension::Three => (
+                (315.0_f64).div(64.0).div(PI).div(h.powi(9)),
+                45.0_f64.div(PI).div(h.powi(6)),
+                45.0_f64.div(PI).div(h.powi(6)),
+            ),
+        };
 
-        let positions = Vec::<Vector>::new();
-        let velocities = Vec::<Vector>::new();
-        let accelerations = Vec::<Vector>::new();
+        let positions = Vec::<Vector<T>>::new();
+        let velocities = Vec::<Vector<T>>::new();
+        let accelerations = Vec::<Vector<T>>::new();
 
-        let grid: Vec<Vec<Vec<Vec<usize>>>> = vec![vec![vec![Vec::<usize>::new(); 110]; 110]; 110];
+        let grid = Self::empty_grid(dimension);
 
         let len = Vector::new(
-            bounds.get_x().div(100.0_f64).max(h),
-            bounds.get_y().div(100.0_f64).max(h),
-            bounds.get_z().div(100.0_f64).max(h),
-        );
+            bounds.get_x().as_f64().div(100.0_f64).max(h),
+            bounds.get_y().as_f64().div(100.0_f64).max(h),
+            bounds.get_z().as_f64().div(100.0_f64).max(h),
+        )
+        .cast();
 
         Self {
-            epsilon,
-            mass,
+            dimension,
+            epsilon: T::of(epsilon),
+            mass: T::of(mass),
             g,
-            rest_density,
-            pdist,
-            pradi,
-            h,
-            acc_limit,
-            damping,
-            bound_repul,
-            kp,
-            visc,
-            tension,
+            forces: Vec::new(),
+            rest_density: T::of(rest_density),
+            pdist: T::of(pdist),
+            pradi: T::of(pradi),
+            h: T::of(h),
+            acc_limit: T::of(acc_limit),
+            damping: T::of(damping),
+            bound_repul: T::of(bound_repul),
+            kp: T::of(kp),
+            visc: T::of(visc),
+            tension: T::of(tension),
             dt,
-            wc,
-            grad_w_2_c,
+            art_visc: T::of(art_visc),
+            sound_speed: T::of(sound_speed),
+            wc: T::of(wc),
+            grad_w_2_c: T::of(grad_w_2_c),
+            lap_w_2_c: T::of(lap_w_2_c),
+            ids: Vec::new(),
+            next_id: 0,
+            births: Vec::new(),
+            temperatures: Vec::new(),
             positions,
             velocities,
             accelerations,
             grid,
             len,
-            densities: Vec::<f64>::new(),
+            densities: Vec::<T>::new(),
+            phases: Vec::new(),
+            stresses: Vec::new(),
+            artificial_stresses: Vec::new(),
+            materials: vec![Material::Fluid],
+            elastic_states: HashMap::new(),
             bounds,
+            reinit_every: 0,
+            reinit_filter: DensityFilter::Shepard,
+            shifting: false,
+            shift_coef: T::of(0.01),
+            surface_threshold: T::of(0.75),
+            step: 0,
         }
     }
 
-    fn w(r: Vector, h: f64, wc: f64) -> f64 {
+    pub fn gravity(&self) -> Vector {
+        self.g.at(self.time())
+    }
+
+    pub fn gravity_field(&self) -> Gravity {
+        self.g
+    }
+
+    pub fn set_gravity(&mut self, g: Gravity) {
+        self.g = g;
+    }
+
+    pub fn add_force(&mut self, force: Box<dyn ForceField>) {
+        self.forces.push(force);
+    }
+
+    pub fn time(&self) -> f64 {
+        self.step as f64 * self.dt.as_f64()
+    }
+
+    pub fn bounds(&self) -> Vector<T> {
+        self.bounds
+    }
+
+    pub fn age(&self, i: usize) -> T {
+        T::of(self.time()) - self.births[i]
+    }
+
+    fn w(r: Vector<T>, h: T, wc: T) -> T {
         let distance = r.square_size();
         let h2 = h.powi(2);
 
         if h2 < distance {
-            return 0.0;
+            return T::zero();
         }
 
         wc * (h2 - distance).powi(3)
     }
 
-    fn grad_w_2(r: Vector, h: f64, grad_w_2_c: f64) -> Vector {
+    fn grad_w_2(r: Vector<T>, h: T, grad_w_2_c: T) -> Vector<T> {
         let distance = r.square_size();
         let h2 = h.powi(2);
         if h2 < distance {
-            return Vector::new(0.0, 0.0, 0.0);
+            return Vector::zero();
         }
 
         let distance = distance.sqrt();
 
-        let constant = -1.0 * grad_w_2_c * (h - distance).powi(2) / distance;
+        let constant = -grad_w_2_c * (h - distance).powi(2) / distance;
         Vector::new(
             constant * r.get_x(),
             constant * r.get_y(),
@@ -127,50 +290,212 @@ impl SPH {
         )
     }
 
-    fn laplacian_w_2(r: Vector, h: f64, grad_w_2_c: f64) -> f64 {
+    fn laplacian_w_2(r: Vector<T>, h: T, lap_w_2_c: T) -> T {
         let distance = r.square_size().sqrt();
         if h < distance {
-            return 0.0;
+            return T::zero();
         }
-        grad_w_2_c * (h - distance)
+        lap_w_2_c * (h - distance)
+    }
+
+    pub fn add_material(&mut self, material: Material) -> usize {
+        self.materials.push(material);
+        self.materials.len() - 1
     }
 
-    pub fn add_particle(&mut self, from: &Vector, to: &Vector) {
+    pub fn add_particle(&mut self, from: &Vector<T>, to: &Vector<T>) {
+        self.add_phase_particle(from, to, 0);
+    }
+
+    pub fn add_phase_particle(&mut self, from: &Vector<T>, to: &Vector<T>, phase: usize) {
+        let start = self.positions.len();
         let epsilon = self.epsilon;
-        let d = self.pdist * 0.84;
+        let d = self.pdist * T::of(0.84);
         let positions = &mut self.positions;
         let velocities = &mut self.velocities;
         let accelerations = &mut self.accelerations;
         let densities = &mut self.densities;
+        let phases = &mut self.phases;
+        let stresses = &mut self.stresses;
+        let artificial_stresses = &mut self.artificial_stresses;
+
+        // A single layer at z = 0 in 2D
+        let (from_z, to_z) = match self.dimension {
+            Dimension::Two => (T::zero(), T::zero()),
+            Dimension::Three => (from.get_z().add(epsilon), to.get_z().sub(epsilon)),
+        };
 
         let mut x = from.get_x().add(epsilon);
         let mut y = from.get_y().add(epsilon);
-        let mut z = from.get_z().add(epsilon);
+        let mut z = from_z;
 
         while x <= to.get_x().sub(epsilon) {
             while y <= to.get_y().sub(epsilon) {
-                while z <= to.get_z().sub(epsilon) {
+                while z <= to_z {
                     positions.push(Vector::new(x, y, z));
-                    velocities.push(Vector::new(0.0, 0.0, 0.0));
-                    accelerations.push(Vector::new(0.0, 0.0, 0.0));
-                    densities.push(0.0);
+                    velocities.push(Vector::zero());
+                    accelerations.push(Vector::zero());
+                    densities.push(T::zero());
+                    phases.push(phase);
+                    stresses.push(Matrix::zero());
+                    artificial_stresses.push(Matrix::zero());
                     z += d;
                 }
                 y += d;
-                z = from.get_y().add(epsilon);
+                z = from_z;
             }
             x += d;
             y = from.get_y().add(epsilon);
         }
+
+        let birth = T::of(self.time());
+        for _ in start..self.positions.len() {
+            self.ids.push(self.next_id);
+            self.next_id += 1;
+            self.births.push(birth);
+            self.temperatures.push(T::of(AMBIENT_TEMPERATURE));
+        }
+
+        if let Material::Elastic(_) = self.materials[phase] {
+            self.build_rest_shape(start);
+        }
+    }
+
+    // Freeze the neighborhood of the particles added since `start`, at the
+    // positions they were added at, and the kernel gradient correction of
+    // each of them
+    fn build_rest_shape(&mut self, start: usize) {
+        let mut cells: HashMap<(i32, i32, i32), Vec<usize>> = HashMap::new();
+        let cell = |p: Vector<T>, h: T| {
+            (
+                p.get_x().div(h).floor().as_f64() as i32,
+                p.get_y().div(h).floor().as_f64() as i32,
+                p.get_z().div(h).floor().as_f64() as i32,
+            )
+        };
+        for i in start..self.positions.len() {
+            cells.entry(cell(self.positions[i], self.h)).or_default().push(i);
+        }
+
+        let volume = self.mass / self.rest_density;
+        for i in start..self.positions.len() {
+            let (cx, cy, cz) = cell(self.positions[i], self.h);
+            let mut neighbors = Vec::new();
+            let mut correction = Matrix::zero();
+            for x in cx - 1..=cx + 1 {
+                for y in cy - 1..=cy + 1 {
+                    for z in cz - 1..=cz + 1 {
+                        for j in cells.get(&(x, y, z)).into_iter().flatten() {
+                            let rest = self.positions[i].subv(self.positions[*j]);
+                            if i == *j || rest.square_size() >= self.h.powi(2) {
+                                continue;
+                            }
+                            neighbors.push(*j);
+                            let grad = Self::grad_w_2(rest, self.h, self.grad_w_2_c);
+                            correction = correction.addm(Matrix::outer(rest.mulf(-volume), grad));
+                        }
+                    }
+                }
+            }
+
+            if self.dimension == Dimension::Two {
+                correction = correction.addm(out_of_plane());
+            }
+
+            let state = ElasticState {
+                rest_position: self.positions[i],
+                rest_neighbors: neighbors,
+                rest_correction: correction.inverse().unwrap_or(Matrix::identity()),
+                deformation: Matrix::identity(),
+                plastic_strain: Matrix::zero(),
+            };
+            self.elastic_states.insert(i, state);
+        }
+    }
+
+    fn granular(&self, i: usize) -> Option<GranularParams> {
+        match self.materials[self.phases[i]] {
+            Material::Granular(params) => Some(params),
+            _ => None,
+        }
+    }
+
+    fn elastic(&self, i: usize) -> Option<ElasticParams> {
+        match self.materials[self.phases[i]] {
+            Material::Elastic(params) => Some(params),
+            _ => None,
+        }
+    }
+
+    // 110 cells along every axis, a single layer along z in 2D
+    fn empty_grid(dimension: Dimension) -> Vec<Vec<Vec<Vec<usize>>>> {
+        let layers = match dimension {
+            Dimension::Two => 1,
+            Dimension::Three => 110,
+        };
+        vec![vec![vec![Vec::<usize>::new(); layers]; 110]; 110]
+    }
+
+    fn clear_grid(&mut self) {
+        for column in self.grid.iter_mut().flatten() {
+            for cell in column {
+                cell.clear();
+            }
+        }
+    }
+
+    // Offsets (plus one) of the neighbouring cells along z, only the own
+    // layer in 2D
+    fn z_cells(&self) -> Range<i32> {
+        match self.dimension {
+            Dimension::Two => 1..2,
+            Dimension::Three => 0..3,
+        }
+    }
+
+    fn for_each_neighbor<F: FnMut(usize)>(&self, position: Vector<T>, mut f: F) {
+        let grid_x = position.get_x().div(self.len.get_x()).as_f64() as i32;
+        let grid_y = position.get_y().div(self.len.get_y()).as_f64() as i32;
+        let grid_z = position.get_z().div(self.len.get_z()).as_f64() as i32;
+
+        for x in 0..3_i32 {
+            if x.sub(1).add(grid_x) < 0 {
+                continue;
+            }
+            for y in 0..3_i32 {
+                if y.sub(1).add(grid_y) < 0 {
+                    continue;
+                }
+                for z in self.z_cells() {
+                    if z.sub(1).add(grid_z) < 0 {
+                        continue;
+                    }
+
+                    for j in &self.grid[grid_x.sub(1).add(x) as usize]
+                        [grid_y.sub(1).add(y) as usize]
+                        [grid_z.sub(1).add(z) as usize]
+                    {
+                        f(*j);
+                    }
+                }
+            }
+        }
+    }
+
+    // The grid is always rebuilt in particle order after particles move, so a
+    // deserialized state gets back the exact same neighbor lists
+    pub fn restore_grid(&mut self) {
+        self.grid = Self::empty_grid(self.dimension);
+        self.construct_grid();
     }
 
     pub fn construct_grid(&mut self) {
         let position = &self.positions;
 
         for i in 0..position.len() {
-            let grid_x = position[i].get_x().div(self.len.get_x()) as usize;
-            let grid_y = position[i].get_y().div(self.len.get_y()) as usize;
-            let grid_z = position[i].get_z().div(self.len.get_z()) as usize;
+            let grid_x = position[i].get_x().div(self.len.get_x()).as_f64() as usize;
+            let grid_y = position[i].get_y().div(self.len.get_y()).as_f64() as usize;
+            let grid_z = position[i].get_z().div(self.len.get_z()).as_f64() as usize;
             if grid_x > 110 || grid_y > 110 || grid_z > 110 {
                 println!(
                     "{} {} {}",
@@ -186,17 +511,18 @@ impl SPH {
     pub fn density(&mut self) {
         let positions = &self.positions;
         let m = &self.mass;
+        let z_cells = self.z_cells();
         let rho = &mut self.densities;
 
         (0..positions.len())
             .into_par_iter()
             .zip_eq(rho)
             .for_each(|(i, rho)| {
-                let grid_x = positions[i].get_x().div(self.len.get_x()) as i32;
-                let grid_y = positions[i].get_y().div(self.len.get_y()) as i32;
-                let grid_z = positions[i].get_z().div(self.len.get_z()) as i32;
+                let grid_x = positions[i].get_x().div(self.len.get_x()).as_f64() as i32;
+                let grid_y = positions[i].get_y().div(self.len.get_y()).as_f64() as i32;
+                let grid_z = positions[i].get_z().div(self.len.get_z()).as_f64() as i32;
 
-                *rho = 0.0;
+                *rho = T::zero();
                 for x in 0..3_i32 {
                     if x.sub(1).add(grid_x) < 0 {
                         continue;
@@ -205,7 +531,7 @@ impl SPH {
                         if y.sub(1).add(grid_y) < 0 {
                             continue;
                         }
-                        for z in 0..3_i32 {
+                        for z in z_cells.clone() {
                             if z.sub(1).add(grid_z) < 0 {
                                 continue;
                             }
@@ -220,28 +546,96 @@ impl SPH {
                         }
                     }
                 }
-                *rho *= m;
+                *rho *= *m;
             });
     }
 
-    pub fn pressure(rho: f64, kp: f64, rho0: f64) -> f64 {
+    pub fn pressure(rho: T, kp: T, rho0: T) -> T {
         kp * (rho - rho0)
     }
 
+    pub fn pressures(&self) -> Vec<T> {
+        self.densities
+            .iter()
+            .map(|rho| Self::pressure(*rho, self.kp, self.rest_density))
+            .collect()
+    }
+
+    // Pressure stiffness and rest density, to evaluate pressures of recorded frames
+    pub fn equation_of_state(&self) -> (T, T) {
+        (self.kp, self.rest_density)
+    }
+
     pub fn accelerate(&mut self) {
+        let stresses: Vec<Matrix<T>> = (0..self.positions.len())
+            .into_par_iter()
+            .map(|i| match self.granular(i) {
+                Some(params) => params.stress(
+                    self.stresses[i],
+                    Self::pressure(self.densities[i], self.kp, self.rest_density),
+                ),
+                None => Matrix::zero(),
+            })
+            .collect();
+
+        // Deformation gradient of elastic particles from their rest neighborhood,
+        // then their plastic strain and stress
+        let volume = self.mass / self.rest_density;
+        let dt = self.dt;
+        let deformations: Vec<(usize, [Matrix<T>; 3])> = self
+            .elastic_states
+            .par_iter()
+            .filter_map(|(i, state)| {
+                let params = self.elastic(*i)?;
+                let mut f = Matrix::zero();
+                for j in &state.rest_neighbors {
+                    let rest = state.rest_position.subv(self.elastic_states[j].rest_position);
+                    let grad = Self::grad_w_2(rest, self.h, self.grad_w_2_c);
+                    let current = self.positions[*j].subv(self.positions[*i]);
+                    f = f.addm(Matrix::outer(current.mulf(volume), grad));
+                }
+                if self.dimension == Dimension::Two {
+                    f = f.addm(out_of_plane());
+                }
+                let f = f.mulm(state.rest_correction);
+                let (piola, plastic) = params.piola(f, state.plastic_strain, dt);
+                Some((*i, [f, plastic, piola.mulm(state.rest_correction.transpose())]))
+            })
+            .collect();
+
+        let mut piola = HashMap::with_capacity(deformations.len());
+        for (i, [f, plastic, p]) in deformations {
+            let state = self.elastic_states.get_mut(&i).unwrap();
+            state.deformation = f;
+            state.plastic_strain = plastic;
+            piola.insert(i, p);
+        }
+
+        let t = self.time();
+        let f_gravity: Vector<T> = self.g.at(t).cast();
+        let z_cells = self.z_cells();
+
         (0..self.positions.len())
             .into_par_iter()
             .zip_eq(&mut self.accelerations)
             .for_each(|(i, accel)| {
-                let f_gravity = self.g;
+                let f_external = self.forces.iter().fold(f_gravity, |a, force| {
+                    let (position, velocity) = (self.positions[i].cast(), self.velocities[i].cast());
+                    a.addv(force.acceleration(position, velocity, t).cast())
+                });
 
-                let mut f_tens = Vector::new(0.0, 0.0, 0.0);
-                let mut f_pres = Vector::new(0.0, 0.0, 0.0);
-                let mut f_visc = Vector::new(0.0, 0.0, 0.0);
+                let mut f_tens = Vector::zero();
+                let mut f_pres = Vector::zero();
+                let mut f_visc = Vector::zero();
+                let mut a_stress = Vector::zero();
+                let fluid_i = matches!(self.materials[self.phases[i]], Material::Fluid);
+                let granular_i = matches!(self.materials[self.phases[i]], Material::Granular(_));
+                let elastic_i = self.elastic_states.get(&i);
+                let rest_neighbors_i = elastic_i.map_or(&[][..], |state| &state.rest_neighbors[..]);
 
-                let grid_x = self.positions[i].get_x().div(self.len.get_x()) as i32;
-                let grid_y = self.positions[i].get_y().div(self.len.get_y()) as i32;
-                let grid_z = self.positions[i].get_z().div(self.len.get_z()) as i32;
+                let grid_x = self.positions[i].get_x().div(self.len.get_x()).as_f64() as i32;
+                let grid_y = self.positions[i].get_y().div(self.len.get_y()).as_f64() as i32;
+                let grid_z = self.positions[i].get_z().div(self.len.get_z()).as_f64() as i32;
 
                 for x in 0..3_i32 {
                     if x.sub(1).add(grid_x) < 0 {
@@ -251,7 +645,7 @@ impl SPH {
                         if y.sub(1).add(grid_y) < 0 {
                             continue;
                         }
-                        for z in 0..3_i32 {
+                        for z in z_cells.clone() {
                             if z.sub(1).add(grid_z) < 0 {
                                 continue;
                             }
@@ -265,30 +659,68 @@ impl SPH {
                                 }
 
                                 let direction = self.positions[i].subv(self.positions[*j]);
+                                let fluid_j = matches!(self.materials[self.phases[*j]], Material::Fluid);
+                                let granular_j = matches!(self.materials[self.phases[*j]], Material::Granular(_));
 
-                                let press =
-                                    Self::pressure(self.densities[i], self.kp, self.rest_density)
-                                        .add(Self::pressure(
-                                            self.densities[*j],
-                                            self.kp,
-                                            self.rest_density,
+                                if granular_i && granular_j {
+                                    let stress = stresses[i]
+                                        .divf(self.densities[i].powi(2))
+                                        .addm(stresses[*j].divf(self.densities[*j].powi(2)));
+                                    let grad = Self::grad_w_2(direction, self.h, self.grad_w_2_c);
+                                    let f = Self::w(direction, self.h, self.wc)
+                                        .div(Self::w(
+                                            Vector::new(self.pdist * T::of(0.84), T::zero(), T::zero()),
+                                            self.h,
+                                            self.wc,
                                         ))
-                                        .div(2.0);
+                                        .powi(4);
+                                    let stress = stress.addm(
+                                        self.artificial_stresses[i]
+                                            .addm(self.artificial_stresses[*j])
+                                            .mulf(f),
+                                    );
+                                    a_stress = a_stress.addv(stress.mulv(grad).mulf(self.mass));
 
-                                let tension = Self::w(direction, self.h, self.wc)
-                                    .mul(self.densities[i])
-                                    .mul(self.tension);
+                                    // Monaghan artificial viscosity keeps the stress field stable
+                                    let approach = self.velocities[i]
+                                        .subv(self.velocities[*j])
+                                        .dot(direction);
+                                    if approach < T::zero() {
+                                        let mu = self.h * approach
+                                            / (direction.square_size() + T::of(0.01) * self.h.powi(2));
+                                        let rho = (self.densities[i] + self.densities[*j]) / T::of(2.0);
+                                        let pi = (-self.art_visc * self.sound_speed * mu
+                                            + T::of(2.0) * self.art_visc * mu.powi(2))
+                                            / rho;
+                                        a_stress = a_stress.subv(grad.mulf(self.mass * pi));
+                                    }
+                                } else if !rest_neighbors_i.contains(j) {
+                                    let press =
+                                        Self::pressure(self.densities[i], self.kp, self.rest_density)
+                                            .add(Self::pressure(
+                                                self.densities[*j],
+                                                self.kp,
+                                                self.rest_density,
+                                            ))
+                                            .div(T::of(2.0));
 
-                                f_tens = f_tens.subv(direction.mulf(tension));
+                                    let pression = press.mul(self.mass).div(self.densities[*j]);
+                                    f_pres = f_pres.subv(
+                                        Self::grad_w_2(direction, self.h, self.grad_w_2_c)
+                                            .mulf(pression),
+                                    );
+                                }
 
-                                let pression = press.mul(self.mass).div(self.densities[*j]);
-                                f_pres = f_pres.subv(
-                                    Self::grad_w_2(direction, self.h, self.grad_w_2_c)
-                                        .mulf(pression),
-                                );
+                                if fluid_i && fluid_j {
+                                    let tension = Self::w(direction, self.h, self.wc)
+                                        .mul(self.densities[i])
+                                        .mul(self.tension);
+
+                                    f_tens = f_tens.subv(direction.mulf(tension));
+                                }
 
                                 let viscosity =
-                                    Self::laplacian_w_2(direction, self.h, self.grad_w_2_c)
+                                    Self::laplacian_w_2(direction, self.h, self.lap_w_2_c)
                                         .mul(self.visc)
                                         .mul(self.mass)
                                         .div(self.densities[*j]);
@@ -301,21 +733,35 @@ impl SPH {
                         }
                     }
                 }
+
+                // Elastic forces act on the rest neighborhood only
+                if let (Material::Elastic(params), Some(state)) = (self.materials[self.phases[i]], elastic_i) {
+                    for j in &state.rest_neighbors {
+                        let rest = state.rest_position.subv(self.elastic_states[j].rest_position);
+                        let grad = Self::grad_w_2(rest, self.h, self.grad_w_2_c);
+                        a_stress = a_stress.addv(
+                            piola[&i].addm(piola[j]).mulv(grad).mulf(volume * volume / self.mass),
+                        );
+
+                        let direction = self.positions[i].subv(self.positions[*j]);
+                        let damping = Self::laplacian_w_2(direction, self.h, self.lap_w_2_c)
+                            .mul(T::of(params.viscosity) * volume);
+                        a_stress = a_stress.addv(
+                            self.velocities[*j].subv(self.velocities[i]).mulf(damping),
+                        );
+                    }
+                }
+
                 let f = f_tens.addv(f_pres).addv(f_visc);
-                *accel = f.divf(self.densities[i]).addv(f_gravity);
+                *accel = f.divf(self.densities[i]).addv(a_stress).addv(f_external);
             });
     }
 
     pub fn update_position(&mut self) {
         let bounds = self.bounds;
+        let planar = self.dimension == Dimension::Two;
 
-        for x in 0..110 {
-            for y in 0..110 {
-                for z in 0..110 {
-                    self.grid[x][y][z].clear();
-                }
-            }
-        }
+        self.clear_grid();
 
         (0..self.positions.len())
             .into_par_iter()
@@ -323,7 +769,8 @@ impl SPH {
             .zip_eq(&mut self.positions)
             .zip_eq(&mut self.velocities)
             .zip_eq(&mut self.accelerations)
-            .for_each(|(((_, position), velocity), acceleration)| {
+            .zip_eq(&self.phases)
+            .for_each(|((((_, position), velocity), acceleration), phase)| {
 
                 if acceleration.get_x().is_nan() {
                     acceleration.set_x(self.acc_limit);
@@ -342,43 +789,44 @@ impl SPH {
                         .divf(accel.sqrt());
                 }
 
-                let mut normal_x = 0.0;
-                let mut normal_y = 0.0;
-                let mut normal_z = 0.0;
+                let (zero, one) = (T::zero(), T::one());
+                let mut normal_x = zero;
+                let mut normal_y = zero;
+                let mut normal_z = zero;
 
-                let mut xdisp = 0.0;
-                let mut ydisp = 0.0;
-                let mut zdisp = 0.0;
+                let mut xdisp = zero;
+                let mut ydisp = zero;
+                let mut zdisp = zero;
 
                 if position.get_x() < self.pradi {
-                    normal_x = 1.0;
+                    normal_x = one;
                     xdisp = self.pradi - position.get_x();
                     position.set_x(self.pradi);
                 }
                 if (bounds.get_x() - position.get_x()) < self.pradi {
-                    normal_x = -1.0;
+                    normal_x = -one;
                     xdisp = self.pradi - (bounds.get_x() - position.get_x());
                     position.set_x(bounds.get_x().sub(self.pradi));
                 }
 
                 if position.get_y() < self.pradi {
-                    normal_y = 1.0;
+                    normal_y = one;
                     ydisp = self.pradi - position.get_y();
                     position.set_y(self.pradi);
                 }
                 if (bounds.get_y() - position.get_y()) < self.pradi {
-                    normal_y = -1.0;
+                    normal_y = -one;
                     ydisp = self.pradi - (bounds.get_y() - position.get_y());
                     position.set_y(bounds.get_z().sub(self.pradi));
                 }
 
-                if position.get_z() < self.pradi {
-                    normal_z = 1.0;
+                if !planar && position.get_z() < self.pradi {
+                    normal_z = one;
                     zdisp = self.pradi - position.get_z();
                     position.set_z(self.pradi);
                 }
-                if (bounds.get_z() - position.get_z()) < self.pradi {
-                    normal_z = -1.0;
+                if !planar && (bounds.get_z() - position.get_z()) < self.pradi {
+                    normal_z = -one;
                     zdisp = self.pradi - (bounds.get_z() - position.get_z());
                     position.set_z(bounds.get_z().sub(self.pradi));
                 }
@@ -397,10 +845,271 @@ impl SPH {
                 let acceleration_vec = Vector::new(x_acceleration, y_acceleration, z_acceleration);
 
                 *acceleration = acceleration.addv(acceleration_vec);
+                if planar {
+                    acceleration.set_z(zero);
+                }
                 *velocity = velocity.addv(acceleration.mulf(self.dt));
+
+                // Granular phases get Coulomb friction against the walls: the
+                // tangential velocity loses up to tan(phi) times the normal
+                // velocity the wall removed, and stops when it has less
+                if let Material::Granular(params) = self.materials[*phase] {
+                    if normal.square_size() > zero {
+                        let normal = normal.divf(normal.square_size().sqrt());
+                        let removed = acceleration_vec.dot(normal).abs() * self.dt;
+                        let normal_v = normal.mulf(velocity.dot(normal));
+                        let tangent_v = velocity.subv(normal_v);
+                        let tangent_speed = tangent_v.square_size().sqrt();
+                        let slowdown = T::of(params.friction_angle.to_radians().tan()) * removed;
+                        *velocity = if tangent_speed > slowdown {
+                            normal_v.addv(tangent_v.mulf(one - slowdown / tangent_speed))
+                        } else {
+                            normal_v
+                        };
+                    }
+                }
                 *position = position.addv(velocity.mulf(self.dt));
             });
 
+        self.step += 1;
         self.construct_grid();
+        self.update_stress();
+    }
+
+    // Granular phases integrate their stress from the new velocity field
+    fn update_stress(&mut self) {
+        if !self.materials.iter().any(|m| matches!(m, Material::Granular(_))) {
+            return;
+        }
+
+        let stresses: Vec<(Matrix<T>, Matrix<T>)> = (0..self.positions.len())
+            .into_par_iter()
+            .map(|i| {
+                let params = match self.granular(i) {
+                    Some(params) => params,
+                    None => return (self.stresses[i], self.artificial_stresses[i]),
+                };
+
+                let mut grad_v = Matrix::zero();
+                self.for_each_neighbor(self.positions[i], |j| {
+                    if i == j || self.granular(j).is_none() {
+                        return;
+                    }
+                    let direction = self.positions[i].subv(self.positions[j]);
+                    let grad = Self::grad_w_2(direction, self.h, self.grad_w_2_c);
+                    let dv = self.velocities[j].subv(self.velocities[i]);
+                    grad_v = grad_v.addm(Matrix::outer(dv, grad).mulf(self.mass / self.densities[j]));
+                });
+
+                let pressure = Self::pressure(self.densities[i], self.kp, self.rest_density);
+                let mut deviatoric = params.update_stress(self.stresses[i], grad_v, pressure, self.dt);
+                if !deviatoric.double_dot(deviatoric).is_finite() {
+                    deviatoric = Matrix::zero();
+                }
+                let stress = params.stress(deviatoric, pressure);
+                (deviatoric, params.artificial_stress(stress, self.densities[i], 0.3))
+            })
+            .collect();
+        (self.stresses, self.artificial_stresses) = stresses.into_iter().unzip();
+    }
+
+    fn shepard(&self, i: usize) -> T {
+        let mut rho = T::zero();
+        let mut volume = T::zero();
+        self.for_each_neighbor(self.positions[i], |j| {
+            let wij = Self::w(self.positions[i].subv(self.positions[j]), self.h, self.wc);
+            rho += self.mass * wij;
+            volume += self.mass / self.densities[j] * wij;
+        });
+        rho / volume
+    }
+
+    // First order moving least squares correction (Colagrossi & Landrini)
+    fn mls(&self, i: usize) -> Option<T> {
+        let mut a = [[T::zero(); 4]; 4];
+        self.for_each_neighbor(self.positions[i], |j| {
+            let r = self.positions[i].subv(self.positions[j]);
+            let wij = Self::w(r, self.h, self.wc) * self.mass / self.densities[j];
+            let p = [T::one(), r.get_x(), r.get_y(), r.get_z()];
+            for l in 0..4 {
+                for m in 0..4 {
+                    a[l][m] += wij * p[l] * p[m];
+                }
+            }
+        });
+
+        // No z moment in 2D, keep the system invertible
+        if self.dimension == Dimension::Two {
+            a[3][3] = T::one();
+        }
+
+        let beta = solve4(a, [T::one(), T::zero(), T::zero(), T::zero()])?;
+
+        let mut rho = T::zero();
+        self.for_each_neighbor(self.positions[i], |j| {
+            let r = self.positions[i].subv(self.positions[j]);
+            let correction = beta[0] + beta[1] * r.get_x() + beta[2] * r.get_y() + beta[3] * r.get_z();
+            rho += self.mass * Self::w(r, self.h, self.wc) * correction;
+        });
+        Some(rho)
+    }
+
+    // Must run right after density(), uses the same neighbor grid
+    pub fn reinitialize_density(&mut self) {
+        if self.step.checked_rem(self.reinit_every) != Some(0) {
+            return;
+        }
+
+        let densities: Vec<T> = (0..self.positions.len())
+            .into_par_iter()
+            .map(|i| match self.reinit_filter {
+                DensityFilter::Shepard => self.shepard(i),
+                DensityFilter::Mls => self.mls(i).unwrap_or_else(|| self.shepard(i)),
+            })
+            .collect();
+        self.densities = densities;
+    }
+
+    // Fickian shifting (Lind et al. 2012): move particles down the concentration
+    // gradient, only tangentially near the free surface.
+    pub fn shift_particles(&mut self) {
+        if !self.shifting {
+            return;
+        }
+
+        let max_shift = self.pdist * T::of(0.2);
+        let shifts: Vec<Vector<T>> = (0..self.positions.len())
+            .into_par_iter()
+            .map(|i| {
+                let mut concentration = T::zero();
+                let mut grad_c = Vector::zero();
+                self.for_each_neighbor(self.positions[i], |j| {
+                    let direction = self.positions[i].subv(self.positions[j]);
+                    let volume = self.mass / self.densities[j];
+                    concentration += Self::w(direction, self.h, self.wc) * volume;
+                    if i != j {
+                        grad_c = grad_c.addv(
+                            Self::grad_w_2(direction, self.h, self.grad_w_2_c).mulf(volume),
+                        );
+                    }
+                });
+
+                let mut shift = grad_c.mulf(-self.shift_coef * self.h.powi(2));
+
+                let norm = grad_c.square_size().sqrt();
+                if concentration < self.surface_threshold && norm > T::zero() {
+                    let normal = grad_c.divf(norm);
+                    shift = shift.subv(normal.mulf(shift.dot(normal)));
+                }
+
+                let size = shift.square_size().sqrt();
+                if size > max_shift {
+                    shift = shift.mulf(max_shift / size);
+                }
+                shift
+            })
+            .collect();
+
+        // Never shift a particle through the walls, update_position handles those
+        let low = self.pradi;
+        let high = self.bounds.subf(self.pradi);
+        for (position, shift) in self.positions.iter_mut().zip(shifts) {
+            let moved = position.addv(shift);
+            for k in 0..3 {
+                if moved.get(k) >= low && moved.get(k) <= high.get(k) {
+                    position.set(k, moved.get(k));
+                }
+            }
+        }
+
+        self.clear_grid();
+        self.construct_grid();
+    }
+}
+
+// Unit z z^T, completes 2D deformation gradients
+fn out_of_plane<T: Real>() -> Matrix<T> {
+    let z = Vector::new(T::zero(), T::zero(), T::one());
+    Matrix::outer(z, z)
+}
+
+// Gaussian elimination with partial pivoting
+fn solve4<T: Real>(mut a: [[T; 4]; 4], mut b: [T; 4]) -> Option<[T; 4]> {
+    for col in 0..4 {
+        let pivot = (col..4)
+            .max_by(|&x, &y| a[x][col].abs().partial_cmp(&a[y][col].abs()).unwrap_or(Ordering::Equal))
+            .unwrap();
+        if a[pivot][col].abs() < T::of(1e-12) {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        let pivot_row = a[col];
+        for row in col + 1..4 {
+            let factor = a[row][col] / pivot_row[col];
+            for (value, pivot) in a[row].iter_mut().zip(pivot_row).skip(col) {
+                *value -= factor * pivot;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+
+    let mut x = [T::zero(); 4];
+    for row in (0..4).rev() {
+        let mut sum = b[row];
+        for k in row + 1..4 {
+            sum -= a[row][k] * x[k];
+        }
+        x[row] = sum / a[row][row];
+    }
+    Some(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Dam break against the corner, stepped like main
+    fn dam_break<T: Real>() -> SPH<T> {
+        let mut sph = SPH::<T>::new(Vector::new(50.0, 50.0, 50.0).cast(), T::of(1.0 / 144.0));
+        sph.add_particle(&Vector::new(0.0, 0.0, 0.0).cast(), &Vector::new(8.0, 8.0, 10.0).cast());
+        sph.construct_grid();
+        sph
+    }
+
+    fn step<T: Real>(sph: &mut SPH<T>) {
+        sph.density();
+        sph.reinitialize_density();
+        sph.shift_particles();
+        sph.accelerate();
+        sph.update_position();
+    }
+
+    fn center(positions: &[Vector<f64>]) -> Vector<f64> {
+        let sum = positions.iter().fold(Vector::zero(), |sum: Vector<f64>, p| sum.addv(*p));
+        sum.divf(positions.len() as f64)
+    }
+
+    // The splash is chaotic, rounding differences grow by about 3x a step
+    // once it starts: particles are compared while it forms, the bulk a
+    // while longer. Tolerances are fractions of h (2.5).
+    #[test]
+    fn f32_follows_f64() {
+        const PARTICLE_STEPS: usize = 8;
+        const BULK_STEPS: usize = 15;
+        let (mut single, mut double) = (dam_break::<f32>(), dam_break::<f64>());
+        let h = double.h;
+        for i in 1..=BULK_STEPS {
+            step(&mut single);
+            step(&mut double);
+            let positions: Vec<Vector<f64>> = single.positions.iter().map(|p| p.cast()).collect();
+            if i <= PARTICLE_STEPS {
+                let gap = positions.iter().zip(&double.positions).map(|(a, b)| a.subv(*b).square_size().sqrt()).fold(0.0, f64::max);
+                assert!(gap < 0.02 * h, "step {}: particles {} apart", i, gap);
+            }
+            let gap = center(&positions).subv(center(&double.positions)).square_size().sqrt();
+            assert!(gap < 0.01 * h, "step {}: centers of mass {} apart", i, gap);
+        }
     }
 }
@@ -0,0 +1,419 @@
+// Frames are handed to the writers as soon as they are simulated instead of
+// being accumulated for the whole run. Every sink runs on its own thread behind
+// a bounded channel: when a writer falls behind, the simulation blocks.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{sync_channel, SyncSender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::{clip::Clip, forces::Gravity, luxrender::Renderer, mesh::{MeshAttributes, MeshWriter}, real::Real, reconstruction::ReconstructionParams, sph::SPH, volume::VolumeWriter, whitewater::Whitewater, DensityPosition};
+
+pub type Frame<T> = Arc<Vec<DensityPosition<T>>>;
+pub type ViewerFrames = Arc<Mutex<Vec<Vec<[f32; 3]>>>>;
+
+// Per-particle attributes recorded in the frames and exported besides id,
+// position and density. Accelerations are only known to the solver, frames
+// don't carry them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attributes {
+    pub velocity: bool,
+    pub pressure: bool,
+    pub acceleration: bool,
+    pub phase: bool,
+    pub age: bool,
+    pub temperature: bool,
+}
+
+// What the exporters wrote before attributes were selectable
+impl Default for Attributes {
+    fn default() -> Self {
+        Self {
+            velocity: true,
+            pressure: true,
+            acceleration: true,
+            phase: false,
+            age: true,
+            temperature: false,
+        }
+    }
+}
+
+impl Attributes {
+    pub fn none() -> Self {
+        Self {
+            velocity: false,
+            pressure: false,
+            acceleration: false,
+            phase: false,
+            age: false,
+            temperature: false,
+        }
+    }
+
+    pub fn all() -> Self {
+        Self {
+            velocity: true,
+            pressure: true,
+            acceleration: true,
+            phase: true,
+            age: true,
+            temperature: true,
+        }
+    }
+
+    // Comma separated names, e.g. "velocity,age", or "all" / "none"
+    pub fn parse(list: &str) -> Option<Self> {
+        let mut attributes = Self::none();
+        for name in list.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match name {
+                "all" => attributes = Self::all(),
+                "none" => attributes = Self::none(),
+                "velocity" | "v" => attributes.velocity = true,
+                "pressure" => attributes.pressure = true,
+                "acceleration" => attributes.acceleration = true,
+                "phase" => attributes.phase = true,
+                "age" => attributes.age = true,
+                "temperature" => attributes.temperature = true,
+                _ => return None,
+            }
+        }
+        Some(attributes)
+    }
+
+    pub fn record<T: Real>(&self, sph: &SPH<T>, t: T) -> Vec<DensityPosition<T>> {
+        let pressures = if self.pressure { sph.pressures() } else { Vec::new() };
+        (0..sph.positions.len())
+            .map(|i| DensityPosition {
+                velocity: self.velocity.then(|| sph.velocities[i]),
+                pressure: pressures.get(i).copied(),
+                phase: self.phase.then(|| sph.phases[i] as u32),
+                age: self.age.then(|| sph.age(i)),
+                temperature: self.temperature.then(|| sph.temperatures[i]),
+                ..DensityPosition::new(sph.ids[i], sph.positions[i], sph.densities[i], t)
+            })
+            .collect()
+    }
+}
+
+pub trait FrameSink<T: Real>: Send {
+    fn write(&mut self, index: usize, frame: &Frame<T>);
+
+    fn finish(&mut self) {}
+}
+
+pub struct FrameStream<T: Real> {
+    capacity: usize,
+    senders: Vec<SyncSender<(usize, Frame<T>)>>,
+    workers: Vec<JoinHandle<()>>,
+    index: usize,
+}
+
+impl<T: Real> FrameStream<T> {
+    // `capacity` frames may wait in front of each sink
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            senders: Vec::new(),
+            workers: Vec::new(),
+            index: 0,
+        }
+    }
+
+    pub fn add_sink<S: FrameSink<T> + 'static>(&mut self, mut sink: S) {
+        let (sender, receiver) = sync_channel::<(usize, Frame<T>)>(self.capacity);
+        self.senders.push(sender);
+        self.workers.push(thread::spawn(move || {
+            for (index, frame) in receiver {
+                sink.write(index, &frame);
+            }
+            sink.finish();
+        }));
+    }
+
+    // Index of the next frame, when resuming a run
+    pub fn skip_to(&mut self, index: usize) {
+        self.index = index;
+    }
+
+    // Blocks while a sink queue is full
+    pub fn push(&mut self, frame: Vec<DensityPosition<T>>) {
+        let frame = Arc::new(frame);
+        for sender in &self.senders {
+            sender.send((self.index, Arc::clone(&frame))).unwrap();
+        }
+        self.index += 1;
+    }
+
+    // Waits for every sink to drain its queue
+    pub fn finish(self) {
+        drop(self.senders);
+        for worker in self.workers {
+            worker.join().unwrap();
+        }
+    }
+}
+
+// One JSON file per frame: <dir>/frame_<index>.json
+pub struct DiskSink {
+    dir: String,
+}
+
+impl DiskSink {
+    pub fn new(dir: &str) -> Self {
+        fs::create_dir_all(dir).unwrap();
+        Self { dir: dir.to_string() }
+    }
+}
+
+impl<T: Real> FrameSink<T> for DiskSink {
+    fn write(&mut self, index: usize, frame: &Frame<T>) {
+        let file = File::create(format!("{}/frame_{}.json", self.dir, index)).unwrap();
+        let mut writer = BufWriter::new(file);
+        if let Err(e) = serde_json::to_writer(&mut writer, frame.as_ref()) {
+            eprintln!("Couldn't write frame {}: {}", index, e);
+        }
+        writer.flush().unwrap();
+    }
+}
+
+// Positions the viewer may keep over a whole run, 12 bytes a particle
+const VIEWER_BUDGET: usize = 256 << 20;
+
+// Keeps every `stride`-th frame as f32 positions for the live viewer, at most
+// `max_frames` of them.
+pub struct ViewerSink {
+    stride: usize,
+    max_frames: usize,
+    frames: ViewerFrames,
+}
+
+impl ViewerSink {
+    pub fn new(stride: usize, max_frames: usize) -> Self {
+        Self {
+            stride: stride.max(1),
+            max_frames,
+            frames: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // Frames spread evenly over a run of `frames` frames, as many as fit in
+    // VIEWER_BUDGET, so its memory doesn't grow with the particles or the run
+    pub fn for_run(particles: usize, frames: usize) -> Self {
+        let max_frames = (VIEWER_BUDGET / (12 * particles.max(1))).max(1);
+        Self::new(frames.div_ceil(max_frames), max_frames)
+    }
+
+    pub fn frames(&self) -> ViewerFrames {
+        Arc::clone(&self.frames)
+    }
+}
+
+impl<T: Real> FrameSink<T> for ViewerSink {
+    fn write(&mut self, index: usize, frame: &Frame<T>) {
+        let mut frames = self.frames.lock().unwrap();
+        if index.checked_rem(self.stride) != Some(0) || frames.len() >= self.max_frames {
+            return;
+        }
+        frames.push(
+            frame
+                .iter()
+                .map(|p| {
+                    [
+                        p.vector.get_x().as_f64() as f32,
+                        p.vector.get_y().as_f64() as f32,
+                        p.vector.get_z().as_f64() as f32,
+                    ]
+                })
+                .collect(),
+        );
+    }
+}
+
+// Frame to mesh and the frames blended into it, by distance
+type MeshJob<T> = (usize, Frame<T>, Vec<(usize, Frame<T>)>);
+
+// Writers of the surfaces and of the volumes
+pub type Writers = (Vec<Box<dyn MeshWriter>>, Vec<Box<dyn VolumeWriter>>);
+
+// Meshes frames from `start` on with a pool of renderers, one per worker
+// thread. The field is also handed to the volume writers, the surface is only
+// extracted when there are mesh writers.
+pub struct MeshSink<T: Real> {
+    start: usize,
+    // `temporal_frames`: a frame is only meshed once as many have followed it
+    radius: usize,
+    // The last frames, the `pending` newest not handed to the workers yet
+    window: VecDeque<(usize, Frame<T>)>,
+    pending: usize,
+    writers: Arc<Vec<Box<dyn MeshWriter>>>,
+    sender: Option<SyncSender<MeshJob<T>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: Real> MeshSink<T> {
+    pub fn new(
+        workers: usize,
+        start: usize,
+        (h, mass): (T, T),
+        params: ReconstructionParams,
+        clip: Clip,
+        (writers, volumes): Writers,
+        attributes: MeshAttributes,
+    ) -> Self {
+        let (sender, receiver) = sync_channel::<MeshJob<T>>(workers);
+        let writers = Arc::new(writers);
+        let volumes = Arc::new(volumes);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let done = Arc::new(AtomicUsize::new(0));
+
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                let done = Arc::clone(&done);
+                let writers = Arc::clone(&writers);
+                let volumes = Arc::clone(&volumes);
+                let clip = clip.clone();
+                thread::spawn(move || {
+                    let mut renderer: Option<Renderer<T>> = None;
+                    loop {
+                        let next = receiver.lock().unwrap().recv();
+                        let (index, frame, neighbors) = match next {
+                            Ok(next) => next,
+                            Err(_) => break,
+                        };
+
+                        let renderer = renderer
+                            .get_or_insert_with(|| Renderer::new(index, Arc::clone(&frame), h, mass));
+                        renderer.set_frame(index, frame);
+                        renderer.set_neighbors(neighbors);
+                        renderer.params = params;
+                        renderer.clip = clip.clone();
+                        renderer.attributes = attributes;
+                        let (mesh, volume) = renderer.render(!writers.is_empty(), !volumes.is_empty());
+                        if let Some(mesh) = &mesh {
+                            for writer in writers.iter() {
+                                if let Err(e) = writer.write(index, mesh) {
+                                    eprintln!("Couldn't write mesh {}: {}", index, e);
+                                }
+                            }
+                        }
+                        if let Some(volume) = &volume {
+                            for writer in volumes.iter() {
+                                if let Err(e) = writer.write(index, volume) {
+                                    eprintln!("Couldn't write volume {}: {}", index, e);
+                                }
+                            }
+                        }
+
+                        done.fetch_add(1, Ordering::SeqCst);
+                        print!("{:?} frames meshed\r", done);
+                        std::io::stdout().flush().unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            start,
+            radius: params.temporal_frames,
+            window: VecDeque::new(),
+            pending: 0,
+            writers,
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    // Hands the frame at `position` in the window to the workers, with the
+    // frames at most `radius` away around it
+    fn dispatch(&self, position: usize) {
+        let (index, frame) = &self.window[position];
+        if *index < self.start {
+            return;
+        }
+        let neighbors = self
+            .window
+            .iter()
+            .filter(|(other, _)| other != index && other.abs_diff(*index) <= self.radius)
+            .map(|(other, frame)| (other.abs_diff(*index), Arc::clone(frame)))
+            .collect();
+        if let Some(sender) = &self.sender {
+            sender.send((*index, Arc::clone(frame), neighbors)).unwrap();
+        }
+    }
+}
+
+impl<T: Real> FrameSink<T> for MeshSink<T> {
+    fn write(&mut self, index: usize, frame: &Frame<T>) {
+        // Frames before `start` are still blended into the first meshes
+        if index + self.radius < self.start {
+            return;
+        }
+        self.window.push_back((index, Arc::clone(frame)));
+        self.pending += 1;
+        if self.pending > self.radius {
+            self.dispatch(self.window.len() - self.pending);
+            self.pending -= 1;
+        }
+        while self.window.len() > self.pending + self.radius {
+            self.window.pop_front();
+        }
+    }
+
+    fn finish(&mut self) {
+        // The last frames have no successors left to wait for
+        while self.pending > 0 {
+            self.dispatch(self.window.len() - self.pending);
+            self.pending -= 1;
+        }
+        self.sender = None;
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+        println!();
+        for writer in self.writers.iter() {
+            if let Err(e) = writer.finish() {
+                eprintln!("Couldn't finish mesh export: {}", e);
+            }
+        }
+    }
+}
+
+// Post-process whitewater: diffuse particles are advected from the recorded
+// frames, under the gravity of the solver at the time of each frame, and
+// written for LuxRender from `start` on.
+pub struct WhitewaterSink {
+    whitewater: Whitewater,
+    gravity: Gravity,
+    start: usize,
+    radius: f64,
+}
+
+impl WhitewaterSink {
+    pub fn new(whitewater: Whitewater, gravity: Gravity, start: usize, radius: f64) -> Self {
+        Self {
+            whitewater,
+            gravity,
+            start,
+            radius,
+        }
+    }
+}
+
+impl<T: Real> FrameSink<T> for WhitewaterSink {
+    fn write(&mut self, index: usize, frame: &Frame<T>) {
+        let gravity = self.gravity.at(index as f64 * self.whitewater.dt());
+        self.whitewater.step_frame(frame, gravity);
+        if index >= self.start {
+            crate::luxrender::write_diffuse(index, &self.whitewater.particles, self.radius);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+// Naive surface nets, the alternative to marching cubes over the same field.
+// Every cube crossed by the surface gets one vertex, the mean of the crossings
+// on its edges, and every lattice edge crossed by the surface a quad joining
+// the vertices of the four cubes around it. Fewer triangles than marching
+// cubes and no slivers, at the cost of sharp features.
+
+use std::collections::HashMap;
+
+use rayon::prelude::*;
+
+use crate::{
+    clip::Clip,
+    luxrender::vertex_interpret,
+    real::Real,
+    vectors::Vector,
+    voxel::{self, VoxelGrid, BLOCK},
+};
+
+// Runs over the blocks in parallel like marching cubes: a vertex belongs to
+// the block holding the lower corner of its cube, a quad to the block holding
+// the lower end of its edge. Returns the vertices and triangles.
+pub fn polygonize<T: Real>(
+    field: &VoxelGrid<T>,
+    iso: T,
+    min_vector: Vector<T>,
+    baselen: T,
+    clip: &Clip,
+) -> (Vec<Vector<T>>, Vec<[usize; 3]>) {
+    let blocks = field.active_blocks();
+
+    // Vertices of the cubes crossing the surface, by owning block
+    let vertices: Vec<Vec<(u64, Vector<T>)>> = blocks
+        .par_iter()
+        .map(|block| {
+            let values = field.padded(*block);
+            let origin = voxel::unpack(*block).map(|c| c * BLOCK);
+            let value = |p: [usize; 3]| values[voxel::padded_offset(std::array::from_fn(|k| p[k] - origin[k]))];
+
+            let mut vertices = Vec::new();
+            for corner in VoxelGrid::<T>::points(*block) {
+                let mut sum = Vector::zero();
+                let mut crossings = 0;
+                for axis in 0..3 {
+                    let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+                    for side in 0..4 {
+                        let mut a = corner;
+                        a[u] += side & 1;
+                        a[v] += side >> 1;
+                        let mut b = a;
+                        b[axis] += 1;
+                        let (v_a, v_b) = (value(a), value(b));
+                        if (v_a > iso) != (v_b > iso) {
+                            sum = sum.addv(vertex_interpret((a, v_a), (b, v_b), min_vector, baselen, iso, clip));
+                            crossings += 1;
+                        }
+                    }
+                }
+                if crossings > 0 {
+                    let mut vertex = sum.divf(T::of(crossings as f64));
+                    // The mean of crossings on a curved wall lies behind it
+                    if !clip.is_empty() {
+                        vertex = clip.project(vertex.cast()).cast();
+                    }
+                    vertices.push((voxel::pack(corner), vertex));
+                }
+            }
+            vertices
+        })
+        .collect();
+
+    let mut first = 0;
+    let mut indices: HashMap<u64, HashMap<u64, usize>> = HashMap::new();
+    for (block, vertices) in blocks.iter().zip(&vertices) {
+        let index = vertices.iter().enumerate().map(|(i, (key, _))| (*key, first + i)).collect();
+        indices.insert(*block, index);
+        first += vertices.len();
+    }
+    let positions: Vec<Vector<T>> = vertices.into_iter().flatten().map(|(_, p)| p).collect();
+
+    let triangles: Vec<Vec<[usize; 3]>> = blocks
+        .par_iter()
+        .map(|block| {
+            let values = field.padded(*block);
+            let origin = voxel::unpack(*block).map(|c| c * BLOCK);
+            let value = |p: [usize; 3]| values[voxel::padded_offset(std::array::from_fn(|k| p[k] - origin[k]))];
+            let vertex = |cube: [usize; 3]| indices[&voxel::pack(cube.map(|c| c / BLOCK))][&voxel::pack(cube)];
+
+            let mut triangles = Vec::new();
+            for a in VoxelGrid::<T>::points(*block) {
+                for axis in 0..3 {
+                    let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+                    let mut b = a;
+                    b[axis] += 1;
+                    let inside = value(a) > iso;
+                    // The lattice border is outside the padding, never crossed
+                    if inside == (value(b) > iso) || a[u] == 0 || a[v] == 0 {
+                        continue;
+                    }
+
+                    // Cubes around the edge, counterclockwise seen from +axis
+                    let mut cubes = [a; 4];
+                    cubes[0][u] -= 1;
+                    cubes[0][v] -= 1;
+                    cubes[1][v] -= 1;
+                    cubes[3][u] -= 1;
+                    let mut quad = cubes.map(vertex);
+                    // Facing away from the inside end of the edge
+                    if !inside {
+                        quad.reverse();
+                    }
+
+                    // Split along the shorter diagonal
+                    let diagonal = |i: usize, j: usize| positions[quad[i]].subv(positions[quad[j]]).square_size();
+                    if diagonal(0, 2) <= diagonal(1, 3) {
+                        triangles.push([quad[0], quad[1], quad[2]]);
+                        triangles.push([quad[0], quad[2], quad[3]]);
+                    } else {
+                        triangles.push([quad[0], quad[1], quad[3]]);
+                        triangles.push([quad[1], quad[2], quad[3]]);
+                    }
+                }
+            }
+            triangles
+        })
+        .collect();
+
+    (positions, triangles.into_iter().flatten().collect())
+}
@@ -1,11 +1,21 @@
 use serde::{Serialize, Deserialize};
 
- impl Vector {
-    pub  fn new(x: f64, y: f64, z: f64) -> Self {
+use crate::real::Real;
+
+ impl<T: Real> Vector<T> {
+    pub  fn new(x: T, y: T, z: T) -> Self {
         Self { x, y, z }
     }
 
-    pub fn set(&mut self, i: usize, value: f64) {
+    pub fn zero() -> Self {
+        Self::new(T::zero(), T::zero(), T::zero())
+    }
+
+    pub fn cast<U: Real>(&self) -> Vector<U> {
+        Vector::new(U::of(self.x.as_f64()), U::of(self.y.as_f64()), U::of(self.z.as_f64()))
+    }
+
+    pub fn set(&mut self, i: usize, value: T) {
         match i {
             0 => self.set_x(value),
             1 => self.set_y(value),
@@ -14,35 +24,35 @@ use serde::{Serialize, Deserialize};
         }
     }
 
-    pub fn get(&self, i:usize) -> f64 {
+    pub fn get(&self, i:usize) -> T {
         match i {
             0 => self.get_x(),
             1 => self.get_y(),
             2 => self.get_z(),
-            _ => 0.0
+            _ => T::zero()
         }
     }
-    pub fn get_x(&self) -> f64 {
+    pub fn get_x(&self) -> T {
         return self.x;
     }
 
-    pub fn get_y(&self) -> f64 {
+    pub fn get_y(&self) -> T {
         return self.y;
     }
 
-    pub fn get_z(&self) -> f64 {
+    pub fn get_z(&self) -> T {
         return self.z;
     }
 
-    pub fn set_x(&mut self, i: f64){
+    pub fn set_x(&mut self, i: T){
         self.x = i;
     }
 
-    pub fn set_y(&mut self, i: f64){
+    pub fn set_y(&mut self, i: T){
         self.y = i;
     }
 
-    pub fn set_z(&mut self, i: f64){
+    pub fn set_z(&mut self, i: T){
         self.z = i;
     }
 
@@ -52,10 +62,18 @@ use serde::{Serialize, Deserialize};
     //     self.z += dt*acc.get_z();
     // }
 
-    pub fn dot(&self, other: Self) -> f64{
+    pub fn dot(&self, other: Self) -> T{
         self.x * other.x + self.y * other.y + self.z * other.z
     }
 
+    pub fn cross(&self, other: Self) -> Self {
+        Self {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x
+        }
+    }
+
     // pub fn opposite_x(&mut self, dampening: f64){
     //     self.x = -self.x * dampening;
     // }
@@ -68,7 +86,7 @@ use serde::{Serialize, Deserialize};
     //     self.z = -self.z * dampening;
     // }
 
-    pub fn square_size(&self) -> f64 {
+    pub fn square_size(&self) -> T {
         return self.x.powi(2) + self.y.powi(2) + self.z.powi(2);
     }
     //Math operation: 
@@ -81,7 +99,7 @@ use serde::{Serialize, Deserialize};
         }
     }
 
-    pub fn subf(&self, other: f64) -> Self  {
+    pub fn subf(&self, other: T) -> Self  {
         Self {
             x: self.x - other,
             y: self.y - other,
@@ -97,7 +115,7 @@ use serde::{Serialize, Deserialize};
         }
     }
 
-    pub fn addf(&self, other: f64) -> Self  {
+    pub fn addf(&self, other: T) -> Self  {
         Self {
             x: self.x + other,
             y: self.y + other,
@@ -106,7 +124,7 @@ use serde::{Serialize, Deserialize};
     }
 
 
-    pub fn mulf(&self, other: f64) -> Self {
+    pub fn mulf(&self, other: T) -> Self {
         Self {
             x: self.x * other,
             y: self.y * other,
@@ -122,7 +140,7 @@ use serde::{Serialize, Deserialize};
         }
     }
 
-    pub fn divf(&self, other: f64) -> Self {
+    pub fn divf(&self, other: T) -> Self {
         Self {
             x: self.x / other,
             y: self.y / other,
@@ -133,8 +151,9 @@ use serde::{Serialize, Deserialize};
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-pub struct Vector {
-    x: f64,
-    y: f64,
-    z: f64
+#[serde(bound = "")]
+pub struct Vector<T: Real = f64> {
+    x: T,
+    y: T,
+    z: T
 }
\ No newline at end of file
@@ -0,0 +1,235 @@
+// Volumes of the reconstruction field for fog-like rendering of spray and for
+// analysis: the field the Renderer splats for the surface, on the same
+// lattice. Mitsuba .vol and NRRD hold the dense box around the allocated
+// blocks, written a layer of blocks at a time, the sparse format only the
+// blocks, like a VDB leaf level.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+use crate::{
+    mesh::MeshOutput,
+    real::Real,
+    vectors::Vector,
+    voxel::{self, VoxelGrid, BLOCK},
+};
+
+pub struct Volume {
+    // Position of lattice point [0, 0, 0] and the lattice spacing
+    pub origin: Vector,
+    pub voxel_size: f64,
+    // BLOCK³ values per block, x fastest, ordered by key. Zero elsewhere.
+    pub blocks: Vec<(u64, Vec<f32>)>,
+}
+
+impl Volume {
+    pub fn new<T: Real>(field: &VoxelGrid<T>, origin: Vector<T>, voxel_size: T) -> Self {
+        Self {
+            origin: origin.cast(),
+            voxel_size: voxel_size.as_f64(),
+            blocks: field
+                .blocks()
+                .into_iter()
+                .map(|(block, values)| (block, values.iter().map(|v| v.as_f64() as f32).collect()))
+                .collect(),
+        }
+    }
+
+    pub fn position(&self, [l, m, n]: [usize; 3]) -> Vector {
+        Vector::new(l as f64, m as f64, n as f64).mulf(self.voxel_size).addv(self.origin)
+    }
+
+    // Lowest lattice point of the allocated blocks and the number of points
+    // along every axis from there
+    pub fn extent(&self) -> ([usize; 3], [usize; 3]) {
+        if self.blocks.is_empty() {
+            return ([0; 3], [0; 3]);
+        }
+        let mut low = [usize::MAX; 3];
+        let mut high = [0; 3];
+        for (block, _) in &self.blocks {
+            let corner = voxel::unpack(*block).map(|c| c * BLOCK);
+            for k in 0..3 {
+                low[k] = low[k].min(corner[k]);
+                high[k] = high[k].max(corner[k] + BLOCK);
+            }
+        }
+        (low, std::array::from_fn(|k| high[k] - low[k]))
+    }
+
+    // The dense box of `extent`, one layer of blocks along `axis` at a time,
+    // from the lowest layer or the highest when `reversed`, so only a layer
+    // is ever allocated. `write` gets the size of the layer and its values,
+    // x fastest.
+    pub fn layers(
+        &self,
+        axis: usize,
+        reversed: bool,
+        mut write: impl FnMut([usize; 3], &[f32]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let (low, size) = self.extent();
+        let mut by_layer: BTreeMap<usize, Vec<&(u64, Vec<f32>)>> = BTreeMap::new();
+        for entry in &self.blocks {
+            by_layer.entry(voxel::unpack(entry.0)[axis] * BLOCK).or_default().push(entry);
+        }
+
+        let mut shape = size;
+        shape[axis] = BLOCK;
+        let mut values = vec![0.0; shape[0] * shape[1] * shape[2]];
+        let count = size[axis] / BLOCK;
+        for layer in 0..count {
+            let mut start = low;
+            start[axis] += BLOCK * if reversed { count - 1 - layer } else { layer };
+            values.fill(0.0);
+            for (block, source) in by_layer.get(&start[axis]).into_iter().flatten() {
+                for (point, value) in VoxelGrid::<f32>::points(*block).zip(source) {
+                    let [l, m, n]: [usize; 3] = std::array::from_fn(|k| point[k] - start[k]);
+                    values[l + shape[0] * (m + shape[1] * n)] = *value;
+                }
+            }
+            write(shape, &values)?;
+        }
+        Ok(())
+    }
+}
+
+pub trait VolumeWriter: Send + Sync {
+    fn write(&self, frame: usize, volume: &Volume) -> io::Result<()>;
+}
+
+// Mitsuba grid volume (version 3, float32, one channel). Its bounding box is
+// axis aligned, so the values are reordered into the output axes.
+pub struct MitsubaWriter(pub MeshOutput);
+
+impl VolumeWriter for MitsubaWriter {
+    fn write(&self, frame: usize, volume: &Volume) -> io::Result<()> {
+        let output = &self.0;
+        let axes = &output.axes;
+        let mut file = BufWriter::new(File::create(output.path(frame, "vol")?)?);
+        let (low, size) = volume.extent();
+
+        // Output axis k runs along simulation axis axes[k], backwards when
+        // its sign is negative
+        let resolution: [usize; 3] = std::array::from_fn(|k| size[axes.axes[k]]);
+        let high: [usize; 3] = std::array::from_fn(|k| low[k] + size[k].max(1) - 1);
+        let (a, b) = (axes.point(volume.position(low)), axes.point(volume.position(high)));
+        let (min, max): ([f64; 3], [f64; 3]) = (std::array::from_fn(|k| a[k].min(b[k])), std::array::from_fn(|k| a[k].max(b[k])));
+
+        file.write_all(b"VOL")?;
+        file.write_all(&[3])?;
+        // Encoding 1 is float32
+        for value in [1, resolution[0], resolution[1], resolution[2], 1] {
+            file.write_all(&(value as i32).to_le_bytes())?;
+        }
+        for value in min.iter().chain(&max) {
+            file.write_all(&(*value as f32).to_le_bytes())?;
+        }
+        // The outermost output axis walks the layers
+        let outer = axes.axes[2];
+        let backwards = axes.signs[2] < 0.0;
+        volume.layers(outer, backwards, |shape, values| {
+            for z in 0..BLOCK {
+                for y in 0..resolution[1] {
+                    for x in 0..resolution[0] {
+                        let mut point = [0; 3];
+                        for (k, o) in [x, y].into_iter().enumerate() {
+                            let axis = axes.axes[k];
+                            point[axis] = if axes.signs[k] > 0.0 { o } else { size[axis] - 1 - o };
+                        }
+                        point[outer] = if backwards { BLOCK - 1 - z } else { z };
+                        let value = values[point[0] + shape[0] * (point[1] + shape[1] * point[2])];
+                        file.write_all(&value.to_le_bytes())?;
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        file.flush()
+    }
+}
+
+// Raw NRRD, the values in simulation order with the output axes in the
+// space directions
+pub struct NrrdWriter(pub MeshOutput);
+
+impl VolumeWriter for NrrdWriter {
+    fn write(&self, frame: usize, volume: &Volume) -> io::Result<()> {
+        let output = &self.0;
+        let axes = &output.axes;
+        let mut file = BufWriter::new(File::create(output.path(frame, "nrrd")?)?);
+        let (low, size) = volume.extent();
+
+        // Adding zero turns the -0 of flipped axes into 0
+        let vector = |v: [f64; 3]| format!("({},{},{})", v[0] + 0.0, v[1] + 0.0, v[2] + 0.0);
+        let directions: Vec<String> = (0..3)
+            .map(|k| {
+                let mut step = Vector::new(0.0, 0.0, 0.0);
+                step.set(k, volume.voxel_size);
+                vector(axes.point(step))
+            })
+            .collect();
+
+        writeln!(file, "NRRD0004")?;
+        writeln!(file, "# reconstruction field, frame {}", frame)?;
+        writeln!(file, "type: float")?;
+        writeln!(file, "dimension: 3")?;
+        writeln!(file, "space dimension: 3")?;
+        writeln!(file, "sizes: {} {} {}", size[0], size[1], size[2])?;
+        writeln!(file, "space directions: {}", directions.join(" "))?;
+        writeln!(file, "space origin: {}", vector(axes.point(volume.position(low))))?;
+        writeln!(file, "kinds: space space space")?;
+        writeln!(file, "endian: little")?;
+        writeln!(file, "encoding: raw")?;
+        writeln!(file)?;
+        volume.layers(2, false, |_, values| {
+            for value in values {
+                file.write_all(&value.to_le_bytes())?;
+            }
+            Ok(())
+        })?;
+        file.flush()
+    }
+}
+
+// Sparse blocks, little endian:
+//   "SPHVOL1\0", u32 block side (8), f64 3x4 index to world transform (row
+//   major, output axes), u32 block count,
+//   then per block u32 block coordinates [3] and side³ f32 values, x fastest.
+// Points outside the blocks are zero.
+pub struct SparseWriter(pub MeshOutput);
+
+impl VolumeWriter for SparseWriter {
+    fn write(&self, frame: usize, volume: &Volume) -> io::Result<()> {
+        let output = &self.0;
+        let axes = &output.axes;
+        let mut file = BufWriter::new(File::create(output.path(frame, "sphvol")?)?);
+
+        let origin = axes.point(volume.origin);
+        let columns: [[f64; 3]; 3] = std::array::from_fn(|k| {
+            let mut step = Vector::new(0.0, 0.0, 0.0);
+            step.set(k, volume.voxel_size);
+            axes.point(step)
+        });
+
+        file.write_all(b"SPHVOL1\0")?;
+        file.write_all(&(BLOCK as u32).to_le_bytes())?;
+        for row in 0..3 {
+            for value in [columns[0][row], columns[1][row], columns[2][row], origin[row]] {
+                file.write_all(&value.to_le_bytes())?;
+            }
+        }
+        file.write_all(&(volume.blocks.len() as u32).to_le_bytes())?;
+        for (block, values) in &volume.blocks {
+            for c in voxel::unpack(*block) {
+                file.write_all(&(c as u32).to_le_bytes())?;
+            }
+            for value in values {
+                file.write_all(&value.to_le_bytes())?;
+            }
+        }
+        file.flush()
+    }
+}
@@ -0,0 +1,139 @@
+// Scalar field sampled on the marching cubes lattice. Lattice points are
+// addressed by integer coordinates packed into a u64 and stored in sparse
+// BLOCK³ blocks, only the blocks reached by a particle kernel are allocated.
+
+use std::collections::HashMap;
+
+use rayon::prelude::*;
+
+use crate::real::Real;
+
+pub const BLOCK: usize = 8;
+const VOLUME: usize = BLOCK * BLOCK * BLOCK;
+
+// Bits per coordinate, leaves room to tag a key with an axis (edge keys)
+const BITS: u32 = 20;
+pub const MASK: u64 = (1 << BITS) - 1;
+
+pub fn pack([l, m, n]: [usize; 3]) -> u64 {
+    assert!(l as u64 <= MASK && m as u64 <= MASK && n as u64 <= MASK, "lattice point {:?} out of range", [l, m, n]);
+    l as u64 | (m as u64) << BITS | (n as u64) << (2 * BITS)
+}
+
+pub fn unpack(key: u64) -> [usize; 3] {
+    [
+        (key & MASK) as usize,
+        (key >> BITS & MASK) as usize,
+        (key >> (2 * BITS) & MASK) as usize,
+    ]
+}
+
+// Key of the lattice edge going from `corner` one step along `axis`
+pub fn edge_key(corner: [usize; 3], axis: usize) -> u64 {
+    pack(corner) << 2 | axis as u64
+}
+
+// Offset of a point, relative to the block origin, in `VoxelGrid::padded`
+pub fn padded_offset([l, m, n]: [usize; 3]) -> usize {
+    l + (BLOCK + 1) * (m + (BLOCK + 1) * n)
+}
+
+pub struct VoxelGrid<T: Real = f64> {
+    blocks: HashMap<u64, Vec<T>>,
+}
+
+impl<T: Real> VoxelGrid<T> {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+    }
+
+    pub fn from_blocks(blocks: impl IntoIterator<Item = (u64, Vec<T>)>) -> Self {
+        Self {
+            blocks: blocks.into_iter().collect(),
+        }
+    }
+
+    // Allocated blocks ordered by key, BLOCK³ values each, x fastest
+    pub fn blocks(&self) -> Vec<(u64, &[T])> {
+        let mut blocks: Vec<(u64, &[T])> = self.blocks.iter().map(|(key, values)| (*key, &values[..])).collect();
+        blocks.sort_unstable_by_key(|(key, _)| *key);
+        blocks
+    }
+
+    // Lowers the values of the points where `outside` holds to at most `max`
+    pub fn clamp_where(&mut self, max: T, outside: impl Fn([usize; 3]) -> bool + Sync) {
+        self.blocks.par_iter_mut().for_each(|(block, values)| {
+            for (point, value) in Self::points(*block).zip(values.iter_mut()) {
+                if outside(point) {
+                    *value = value.min(max);
+                }
+            }
+        });
+    }
+
+    // Allocated blocks and their lower neighbours, ordered by key. A cube
+    // belongs to the block of its lower corner, so the cubes reaching into an
+    // allocated block from below live in a neighbour; cubes anywhere else are
+    // zero and can't hold a surface.
+    pub fn active_blocks(&self) -> Vec<u64> {
+        let mut active = Vec::with_capacity(self.blocks.len() * 8);
+        for block in self.blocks.keys() {
+            let [l, m, n] = unpack(*block);
+            for neighbour in 0..8 {
+                let offset = [neighbour & 1, neighbour >> 1 & 1, neighbour >> 2];
+                if let (Some(l), Some(m), Some(n)) =
+                    (l.checked_sub(offset[0]), m.checked_sub(offset[1]), n.checked_sub(offset[2]))
+                {
+                    active.push(pack([l, m, n]));
+                }
+            }
+        }
+        active.sort_unstable();
+        active.dedup();
+        active
+    }
+
+    // Values of a block and of the first layer of its upper neighbours,
+    // (BLOCK + 1)³ of them indexed by `padded_offset`
+    pub fn padded(&self, block: u64) -> Vec<T> {
+        const SIDE: usize = BLOCK + 1;
+        let mut values = vec![T::zero(); SIDE * SIDE * SIDE];
+        let origin = unpack(block);
+        for neighbour in 0..8 {
+            let offset = [neighbour & 1, neighbour >> 1 & 1, neighbour >> 2];
+            let key = pack(std::array::from_fn(|k| origin[k] + offset[k]));
+            let Some(source) = self.blocks.get(&key) else {
+                continue;
+            };
+            // Whole block for itself, a single layer along every offset axis
+            let range = offset.map(|o| if o == 0 { 0..BLOCK } else { 0..1 });
+            for n in range[2].clone() {
+                for m in range[1].clone() {
+                    for l in range[0].clone() {
+                        let target = [l + offset[0] * BLOCK, m + offset[1] * BLOCK, n + offset[2] * BLOCK];
+                        values[padded_offset(target)] = source[l + BLOCK * (m + BLOCK * n)];
+                    }
+                }
+            }
+        }
+        values
+    }
+
+    // Lattice points of a block
+    pub fn points(block: u64) -> impl Iterator<Item = [usize; 3]> {
+        let [bl, bm, bn] = unpack(block).map(|c| c * BLOCK);
+        (0..VOLUME).map(move |i| [bl + i % BLOCK, bm + i / BLOCK % BLOCK, bn + i / (BLOCK * BLOCK)])
+    }
+}
+
+impl<T: Real> Default for VoxelGrid<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,208 @@
+// Particle export for ParaView: one VTK file per frame (legacy .vtk polydata or
+// XML .vtu) with a point array per exported attribute (id, density, velocity,
+// pressure, acceleration, ...), and a .pvd collection mapping every file to
+// its time.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+};
+
+use crate::{
+    export::{ParticleWriter, Particles},
+    real::Real,
+    vectors::Vector,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VtkFormat {
+    Legacy,
+    Xml,
+}
+
+fn write_vector<T: Real>(file: &mut impl Write, v: &Vector<T>) -> io::Result<()> {
+    writeln!(file, "{} {} {}", v.get_x(), v.get_y(), v.get_z())
+}
+
+pub fn write_legacy<T: Real>(path: &str, particles: &Particles<T>) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let n = particles.len();
+
+    writeln!(file, "# vtk DataFile Version 3.0")?;
+    writeln!(file, "SPH particles")?;
+    writeln!(file, "ASCII")?;
+    writeln!(file, "DATASET POLYDATA")?;
+    writeln!(file, "POINTS {} double", n)?;
+    for p in &particles.positions {
+        write_vector(&mut file, p)?;
+    }
+    writeln!(file, "VERTICES {} {}", n, 2 * n)?;
+    for i in 0..n {
+        writeln!(file, "1 {}", i)?;
+    }
+
+    writeln!(file, "POINT_DATA {}", n)?;
+    for (name, values) in particles.integers() {
+        writeln!(file, "SCALARS {} long 1", name)?;
+        writeln!(file, "LOOKUP_TABLE default")?;
+        for value in values {
+            writeln!(file, "{}", value)?;
+        }
+    }
+    for (name, values) in particles.scalars() {
+        writeln!(file, "SCALARS {} double 1", name)?;
+        writeln!(file, "LOOKUP_TABLE default")?;
+        for value in values {
+            writeln!(file, "{}", value)?;
+        }
+    }
+    for (name, values) in particles.vectors() {
+        writeln!(file, "VECTORS {} double", name)?;
+        for v in values {
+            write_vector(&mut file, v)?;
+        }
+    }
+    file.flush()
+}
+
+pub fn write_vtu<T: Real>(path: &str, particles: &Particles<T>) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let n = particles.len();
+
+    writeln!(file, r#"<?xml version="1.0"?>"#)?;
+    writeln!(file, r#"<VTKFile type="UnstructuredGrid" version="0.1" byte_order="LittleEndian">"#)?;
+    writeln!(file, "<UnstructuredGrid>")?;
+    writeln!(file, r#"<Piece NumberOfPoints="{}" NumberOfCells="{}">"#, n, n)?;
+
+    writeln!(file, "<Points>")?;
+    writeln!(file, r#"<DataArray type="Float64" NumberOfComponents="3" format="ascii">"#)?;
+    for p in &particles.positions {
+        write_vector(&mut file, p)?;
+    }
+    writeln!(file, "</DataArray>")?;
+    writeln!(file, "</Points>")?;
+
+    // One vertex cell (VTK type 1) per particle
+    writeln!(file, "<Cells>")?;
+    writeln!(file, r#"<DataArray type="Int64" Name="connectivity" format="ascii">"#)?;
+    for i in 0..n {
+        writeln!(file, "{}", i)?;
+    }
+    writeln!(file, "</DataArray>")?;
+    writeln!(file, r#"<DataArray type="Int64" Name="offsets" format="ascii">"#)?;
+    for i in 0..n {
+        writeln!(file, "{}", i + 1)?;
+    }
+    writeln!(file, "</DataArray>")?;
+    writeln!(file, r#"<DataArray type="UInt8" Name="types" format="ascii">"#)?;
+    for _ in 0..n {
+        writeln!(file, "1")?;
+    }
+    writeln!(file, "</DataArray>")?;
+    writeln!(file, "</Cells>")?;
+
+    writeln!(file, r#"<PointData Scalars="density">"#)?;
+    for (name, values) in particles.integers() {
+        writeln!(file, r#"<DataArray type="Int64" Name="{}" format="ascii">"#, name)?;
+        for value in values {
+            writeln!(file, "{}", value)?;
+        }
+        writeln!(file, "</DataArray>")?;
+    }
+    for (name, values) in particles.scalars() {
+        writeln!(file, r#"<DataArray type="Float64" Name="{}" format="ascii">"#, name)?;
+        for value in values {
+            writeln!(file, "{}", value)?;
+        }
+        writeln!(file, "</DataArray>")?;
+    }
+    for (name, values) in particles.vectors() {
+        writeln!(file, r#"<DataArray type="Float64" Name="{}" NumberOfComponents="3" format="ascii">"#, name)?;
+        for v in values {
+            write_vector(&mut file, v)?;
+        }
+        writeln!(file, "</DataArray>")?;
+    }
+    writeln!(file, "</PointData>")?;
+
+    writeln!(file, "</Piece>")?;
+    writeln!(file, "</UnstructuredGrid>")?;
+    writeln!(file, "</VTKFile>")?;
+    file.flush()
+}
+
+// Writes <dir>/frame_<index>.vtk|.vtu and keeps <dir>/particles.pvd up to date,
+// so an interrupted run can still be opened. Every frame only rewrites the
+// closing tags after the new entry.
+pub struct VtkWriter {
+    dir: String,
+    format: VtkFormat,
+    collection: File,
+}
+
+const COLLECTION_END: &str = "</Collection>\n</VTKFile>\n";
+
+impl VtkWriter {
+    // Entries of an earlier collection in the directory before frame `first`
+    // are kept, so a resumed run carries on with the frames it did not redo
+    pub fn new(dir: &str, format: VtkFormat, first: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = format!("{}/particles.pvd", dir);
+        let earlier = match fs::read_to_string(&path) {
+            Ok(text) => read_collection(&text, first),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut collection = File::create(path)?;
+        collection.write_all(
+            format!(
+                "<?xml version=\"1.0\"?>\n<VTKFile type=\"Collection\" version=\"0.1\" byte_order=\"LittleEndian\">\n<Collection>\n{}{}",
+                earlier, COLLECTION_END
+            )
+            .as_bytes(),
+        )?;
+        Ok(Self {
+            dir: dir.to_string(),
+            format,
+            collection,
+        })
+    }
+
+    fn add_to_collection(&mut self, time: f64, name: &str) -> io::Result<()> {
+        self.collection.seek(SeekFrom::End(-(COLLECTION_END.len() as i64)))?;
+        let entry = format!(r#"<DataSet timestep="{}" group="" part="0" file="{}"/>"#, time, name);
+        self.collection.write_all(format!("{}\n{}", entry, COLLECTION_END).as_bytes())
+    }
+}
+
+// The DataSet lines of a collection written by VtkWriter for frames before `first`
+fn read_collection(text: &str, first: usize) -> String {
+    text.lines()
+        .filter(|line| line.trim_start().starts_with("<DataSet"))
+        .filter(|line| {
+            let frame = line
+                .split("file=\"frame_")
+                .nth(1)
+                .and_then(|rest| rest.split('.').next())
+                .and_then(|index| index.parse::<usize>().ok());
+            frame.is_some_and(|frame| frame < first)
+        })
+        .map(|line| format!("{}\n", line.trim_start()))
+        .collect()
+}
+
+impl<T: Real> ParticleWriter<T> for VtkWriter {
+    fn write(&mut self, index: usize, time: f64, particles: &Particles<T>) -> io::Result<()> {
+        let name = match self.format {
+            VtkFormat::Legacy => format!("frame_{}.vtk", index),
+            VtkFormat::Xml => format!("frame_{}.vtu", index),
+        };
+        let path = format!("{}/{}", self.dir, name);
+        match self.format {
+            VtkFormat::Legacy => write_legacy(&path, particles)?,
+            VtkFormat::Xml => write_vtu(&path, particles)?,
+        }
+
+        self.add_to_collection(time, &name)
+    }
+}
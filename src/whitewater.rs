@@ -0,0 +1,340 @@
+// Secondary particles (spray, foam and bubbles) generated from the fluid
+// (Ihmsen et al. 2012, Unified spray, foam and bubbles for particle-based fluids)
+
+use std::{collections::HashMap, ops::*};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{real::Real, vectors::Vector, DensityPosition};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DiffuseKind {
+    Spray,
+    Foam,
+    Bubble,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DiffuseParticle {
+    pub position: Vector,
+    pub velocity: Vector,
+    pub kind: DiffuseKind,
+    pub lifetime: f64,
+}
+
+// Clamp a potential into [0, 1]
+fn clamp_potential(i: f64, (min, max): (f64, f64)) -> f64 {
+    (i.min(max) - i.min(min)).div(max - min)
+}
+
+// Serializable (rng included) so live whitewater can be checkpointed
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Whitewater {
+    h: f64,
+    dt: f64,
+    bounds: Vector,
+
+    // (min, max) thresholds of each potential
+    pub trapped_air: (f64, f64),
+    pub wave_crest: (f64, f64),
+    pub kinetic: (f64, f64),
+
+    // Spawn rates
+    pub k_ta: f64,
+    pub k_wc: f64,
+
+    pub lifetime: f64,
+    pub buoyancy: f64,
+    pub drag: f64,
+    pub max_particles: usize,
+
+    // Neighbor count classification: below spray => spray, above bubble => bubble
+    pub spray_neighbors: usize,
+    pub bubble_neighbors: usize,
+
+    rng: ChaCha12Rng,
+    // Positions of the previous recorded frame by particle id
+    previous: HashMap<u64, Vector>,
+    pub particles: Vec<DiffuseParticle>,
+}
+
+impl Whitewater {
+    pub fn new(h: f64, dt: f64, bounds: Vector) -> Self {
+        Self {
+            h,
+            dt,
+            bounds,
+            trapped_air: (20.0, 200.0),
+            wave_crest: (2.0, 8.0),
+            kinetic: (1000.0, 50000.0),
+            k_ta: 100.0,
+            k_wc: 100.0,
+            lifetime: 3.0,
+            buoyancy: 0.8,
+            drag: 0.5,
+            max_particles: 200000,
+            spray_neighbors: 6,
+            bubble_neighbors: 20,
+            rng: ChaCha12Rng::seed_from_u64(0),
+            previous: HashMap::new(),
+            particles: Vec::new(),
+        }
+    }
+
+    fn weight(&self, r: f64) -> f64 {
+        if r >= self.h {
+            return 0.0;
+        }
+        1.0 - r / self.h
+    }
+
+    fn cell(&self, p: Vector) -> (i32, i32, i32) {
+        (
+            p.get_x().div(self.h).floor() as i32,
+            p.get_y().div(self.h).floor() as i32,
+            p.get_z().div(self.h).floor() as i32,
+        )
+    }
+
+    fn build_grid(&self, positions: &[Vector]) -> HashMap<(i32, i32, i32), Vec<usize>> {
+        let mut grid: HashMap<(i32, i32, i32), Vec<usize>> = HashMap::new();
+        for (i, p) in positions.iter().enumerate() {
+            grid.entry(self.cell(*p)).or_default().push(i);
+        }
+        grid
+    }
+
+    fn neighbors(
+        &self,
+        grid: &HashMap<(i32, i32, i32), Vec<usize>>,
+        positions: &[Vector],
+        p: Vector,
+    ) -> Vec<(usize, f64)> {
+        let (cx, cy, cz) = self.cell(p);
+        let mut result = Vec::new();
+        for x in cx - 1..=cx + 1 {
+            for y in cy - 1..=cy + 1 {
+                for z in cz - 1..=cz + 1 {
+                    if let Some(cell) = grid.get(&(x, y, z)) {
+                        for j in cell {
+                            let r = p.subv(positions[*j]).square_size().sqrt();
+                            if r < self.h {
+                                result.push((*j, r));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    pub fn dt(&self) -> f64 {
+        self.dt
+    }
+
+    // Live mode, called once per simulation step with the gravity of the
+    // solver at that step
+    pub fn step<T: Real>(&mut self, positions: &[Vector<T>], velocities: &[Vector<T>], gravity: Vector) {
+        let positions: Vec<Vector> = positions.iter().map(|p| p.cast()).collect();
+        let velocities: Vec<Vector> = velocities.iter().map(|v| v.cast()).collect();
+        self.step_f64(&positions, &velocities, gravity);
+    }
+
+    fn step_f64(&mut self, positions: &[Vector], velocities: &[Vector], gravity: Vector) {
+        let grid = self.build_grid(positions);
+        self.advect(&grid, positions, velocities, gravity);
+        self.spawn(&grid, positions, velocities);
+    }
+
+    // Post-process mode, velocities are estimated from consecutive recorded frames
+    pub fn step_frame<T: Real>(&mut self, frame: &[DensityPosition<T>], gravity: Vector) {
+        let positions: Vec<Vector> = frame.iter().map(|p| p.vector.cast()).collect();
+        // Recorded velocities when available, otherwise differences with the
+        // previous frame
+        let velocities: Vec<Vector> = frame
+            .iter()
+            .zip(&positions)
+            .map(|(p, position)| match (p.velocity, self.previous.get(&p.id)) {
+                (Some(velocity), _) => velocity.cast(),
+                (None, Some(previous)) => position.subv(*previous).divf(self.dt),
+                (None, None) => Vector::new(0.0, 0.0, 0.0),
+            })
+            .collect();
+
+        self.step_f64(&positions, &velocities, gravity);
+        self.previous = frame.iter().map(|p| p.id).zip(positions).collect();
+    }
+
+    fn spawn(
+        &mut self,
+        grid: &HashMap<(i32, i32, i32), Vec<usize>>,
+        positions: &[Vector],
+        velocities: &[Vector],
+    ) {
+        let normals: Vec<Vector> = (0..positions.len())
+            .into_par_iter()
+            .map(|i| {
+                let mut normal = Vector::new(0.0, 0.0, 0.0);
+                for (j, r) in self.neighbors(grid, positions, positions[i]) {
+                    normal = normal.addv(positions[i].subv(positions[j]).mulf(self.weight(r)));
+                }
+                let size = normal.square_size().sqrt();
+                if size > 0.0 {
+                    normal = normal.divf(size);
+                }
+                normal
+            })
+            .collect();
+
+        let amounts: Vec<f64> = (0..positions.len())
+            .into_par_iter()
+            .map(|i| {
+                let neighbors = self.neighbors(grid, positions, positions[i]);
+                let v_i = velocities[i];
+                let speed = v_i.square_size().sqrt();
+
+                let mut v_diff = 0.0;
+                let mut curvature = 0.0;
+                for (j, r) in &neighbors {
+                    if i == *j {
+                        continue;
+                    }
+                    let x_ij = positions[i].subv(positions[*j]);
+                    let v_ij = v_i.subv(velocities[*j]);
+                    let v_size = v_ij.square_size().sqrt();
+                    if v_size > 0.0 && *r > 0.0 {
+                        v_diff += v_size
+                            * (1.0 - v_ij.divf(v_size).dot(x_ij.divf(*r)))
+                            * self.weight(*r);
+                    }
+
+                    if *r > 0.0 && x_ij.divf(*r).dot(normals[i]) > 0.0 {
+                        curvature += (1.0 - normals[i].dot(normals[*j])) * self.weight(*r);
+                    }
+                }
+
+                // Only convex crests moving along their normal produce spray
+                if speed > 0.0 && v_i.divf(speed).dot(normals[i]) < 0.6 {
+                    curvature = 0.0;
+                }
+                if neighbors.len() > self.bubble_neighbors {
+                    curvature = 0.0;
+                }
+
+                let i_ta = clamp_potential(v_diff, self.trapped_air);
+                let i_wc = clamp_potential(curvature, self.wave_crest);
+                let i_k = clamp_potential(0.5 * speed * speed, self.kinetic);
+
+                i_k * (self.k_ta * i_ta + self.k_wc * i_wc) * self.dt
+            })
+            .collect();
+
+        for i in 0..positions.len() {
+            if self.particles.len() >= self.max_particles {
+                break;
+            }
+
+            let mut amount = amounts[i].floor() as usize;
+            if self.rng.gen::<f64>() < amounts[i].fract() {
+                amount += 1;
+            }
+            if amount == 0 {
+                continue;
+            }
+
+            // Spawn inside a cylinder aligned with the particle velocity
+            let v = velocities[i];
+            let speed = v.square_size().sqrt();
+            let axis = if speed > 0.0 { v.divf(speed) } else { Vector::new(0.0, 0.0, 1.0) };
+            let helper = if axis.get_x().abs() < 0.9 {
+                Vector::new(1.0, 0.0, 0.0)
+            } else {
+                Vector::new(0.0, 1.0, 0.0)
+            };
+            let e1 = axis.cross(helper);
+            let e1 = e1.divf(e1.square_size().sqrt());
+            let e2 = axis.cross(e1);
+
+            for _ in 0..amount {
+                let r = self.h * 0.5 * self.rng.gen::<f64>().sqrt();
+                let theta = self.rng.gen::<f64>() * std::f64::consts::TAU;
+                let height = self.rng.gen::<f64>() * speed * self.dt;
+
+                let offset = e1.mulf(r * theta.cos()).addv(e2.mulf(r * theta.sin()));
+                self.particles.push(DiffuseParticle {
+                    position: positions[i].addv(offset).addv(axis.mulf(height)),
+                    velocity: offset.addv(v),
+                    kind: DiffuseKind::Spray,
+                    lifetime: self.lifetime * (0.5 + 0.5 * self.rng.gen::<f64>()),
+                });
+            }
+        }
+    }
+
+    fn advect(
+        &mut self,
+        grid: &HashMap<(i32, i32, i32), Vec<usize>>,
+        positions: &[Vector],
+        velocities: &[Vector],
+        gravity: Vector,
+    ) {
+        let particles = std::mem::take(&mut self.particles);
+        let this = &*self;
+
+        let particles: Vec<DiffuseParticle> = particles
+            .into_par_iter()
+            .filter_map(|mut d| {
+                let neighbors = this.neighbors(grid, positions, d.position);
+
+                let mut sum_k = 0.0;
+                let mut fluid_v = Vector::new(0.0, 0.0, 0.0);
+                for (j, r) in &neighbors {
+                    let k = this.weight(*r);
+                    sum_k += k;
+                    fluid_v = fluid_v.addv(velocities[*j].mulf(k));
+                }
+                if sum_k > 0.0 {
+                    fluid_v = fluid_v.divf(sum_k);
+                }
+
+                d.kind = if neighbors.len() < this.spray_neighbors {
+                    DiffuseKind::Spray
+                } else if neighbors.len() > this.bubble_neighbors {
+                    DiffuseKind::Bubble
+                } else {
+                    DiffuseKind::Foam
+                };
+
+                match d.kind {
+                    DiffuseKind::Spray => {
+                        d.velocity = d.velocity.addv(gravity.mulf(this.dt));
+                        d.position = d.position.addv(d.velocity.mulf(this.dt));
+                    }
+                    DiffuseKind::Foam => {
+                        d.velocity = fluid_v;
+                        d.position = d.position.addv(fluid_v.mulf(this.dt));
+                        d.lifetime -= this.dt;
+                    }
+                    DiffuseKind::Bubble => {
+                        let buoyancy = gravity.mulf(-this.buoyancy * this.dt);
+                        let drag = fluid_v.subv(d.velocity).mulf(this.drag);
+                        d.velocity = d.velocity.addv(buoyancy).addv(drag);
+                        d.position = d.position.addv(d.velocity.mulf(this.dt));
+                    }
+                }
+
+                let p = d.position;
+                let inside = (0..3).all(|k| p.get(k) >= 0.0 && p.get(k) <= this.bounds.get(k));
+                if d.lifetime <= 0.0 || !inside {
+                    return None;
+                }
+                Some(d)
+            })
+            .collect();
+
+        self.particles = particles;
+    }
+}